@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vcl_formatter_fuzz::generate::File;
+
+fuzz_target!(|file: File| {
+    let source = file.to_string();
+    assert!(
+        vcl_formatter_fuzz::check_formatting(&source),
+        "generated VCL was rejected:\n{source}"
+    );
+});
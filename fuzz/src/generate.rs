@@ -0,0 +1,384 @@
+//! A model of (a subset of) VCL that always renders to valid code, so that the fuzzer can spend
+//! its time on layout and comment placement instead of on getting past the parser.
+
+use std::fmt;
+
+use arbitrary::Arbitrary;
+
+const NAMES: &[&str] = &[
+    "req.url",
+    "req.http.host",
+    "req.http.X-Forwarded-For",
+    "bereq.backend",
+    "beresp.ttl",
+    "resp.status",
+    "client.ip",
+    "std.tolower",
+    "regsub",
+    "vcl_recv",
+    "default",
+    "a",
+];
+
+const STRINGS: &[&str] = &[
+    "\"\"",
+    "\"/\"",
+    "\"example.com\"",
+    "\"127.0.0.1\"",
+    "\"^/static/.*\"",
+    "{\"long \"string\"\"}",
+    "\"\"\"triple\"\"\"",
+];
+
+#[derive(Arbitrary, Debug)]
+pub struct File {
+    declarations: Vec<Declaration>,
+    trivia: Vec<Trivia>,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Declaration {
+    Include(Str),
+    Import(Name),
+    Acl(Name, Vec<(Str, Option<u8>)>),
+    Backend(Name, Vec<(Name, Expr)>),
+    BackendNone(Name),
+    Probe(Name, Vec<(Name, Expr)>),
+    Sub(Name, Vec<Statement>),
+}
+
+#[derive(Arbitrary, Debug)]
+enum Statement {
+    Set(Name, AssignOp, Expr),
+    Unset(Name),
+    Call(Name),
+    IdentCall(Name, Vec<Expr>),
+    Return(Name, Option<Vec<Expr>>),
+    If {
+        condition: Expr,
+        body: Vec<Statement>,
+        elseifs: Vec<(Expr, Vec<Statement>)>,
+        else_body: Option<Vec<Statement>>,
+    },
+}
+
+#[derive(Arbitrary, Debug)]
+enum Expr {
+    Ident(Name),
+    Str(Str),
+    Number(u16),
+    Duration(u16),
+    Bool(bool),
+    Call(Name, Vec<Expr>),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+    Parenthesized(Box<Expr>),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Name(u8);
+
+#[derive(Arbitrary, Debug)]
+struct Str(u8);
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+enum AssignOp {
+    Assign,
+    Add,
+    Subtract,
+}
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+enum BinaryOp {
+    Plus,
+    Minus,
+    Multiply,
+    Divide,
+    And,
+    Or,
+    Equals,
+    NotEquals,
+    Matches,
+    NotMatches,
+    Greater,
+    Lesser,
+}
+
+/// Whitespace and comments placed before a token.
+#[derive(Arbitrary, Debug, Clone, Copy)]
+enum Trivia {
+    Space,
+    Newline,
+    BlankLine,
+    LineComment,
+    HashComment,
+    BlockComment,
+    MultilineBlockComment,
+}
+
+impl Name {
+    fn as_str(&self) -> &'static str {
+        NAMES[self.0 as usize % NAMES.len()]
+    }
+}
+
+impl Str {
+    fn as_str(&self) -> &'static str {
+        STRINGS[self.0 as usize % STRINGS.len()]
+    }
+}
+
+impl AssignOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            AssignOp::Assign => "=",
+            AssignOp::Add => "+=",
+            AssignOp::Subtract => "-=",
+        }
+    }
+}
+
+impl BinaryOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            BinaryOp::Plus => "+",
+            BinaryOp::Minus => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+            BinaryOp::Equals => "==",
+            BinaryOp::NotEquals => "!=",
+            BinaryOp::Matches => "~",
+            BinaryOp::NotMatches => "!~",
+            BinaryOp::Greater => ">",
+            BinaryOp::Lesser => "<",
+        }
+    }
+}
+
+impl Trivia {
+    fn as_str(self) -> &'static str {
+        match self {
+            Trivia::Space => " ",
+            Trivia::Newline => "\n",
+            Trivia::BlankLine => "\n\n",
+            Trivia::LineComment => " // comment\n",
+            Trivia::HashComment => "\n# comment\n",
+            Trivia::BlockComment => " /* comment */ ",
+            Trivia::MultilineBlockComment => "\n/* first\n   second */\n",
+        }
+    }
+}
+
+/// Writes tokens separated by trivia taken from the model in turn.
+struct Renderer<'a> {
+    out: String,
+    trivia: &'a [Trivia],
+    next_trivia: usize,
+}
+
+impl<'a> Renderer<'a> {
+    fn token(&mut self, token: &str) {
+        if !self.out.is_empty() {
+            let trivia = match self.trivia.get(self.next_trivia % self.trivia.len().max(1)) {
+                Some(t) => *t,
+                None => Trivia::Space,
+            };
+            self.next_trivia += 1;
+            self.out.push_str(trivia.as_str());
+        }
+        self.out.push_str(token);
+    }
+
+    fn declaration(&mut self, declaration: &Declaration) {
+        match declaration {
+            Declaration::Include(name) => {
+                self.token("include");
+                self.token(name.as_str());
+                self.token(";");
+            }
+            Declaration::Import(name) => {
+                self.token("import");
+                self.token(name.as_str());
+                self.token(";");
+            }
+            Declaration::Acl(name, entries) => {
+                self.token("acl");
+                self.token(name.as_str());
+                self.token("{");
+                for (value, mask) in entries {
+                    self.token(value.as_str());
+                    if let Some(mask) = mask {
+                        self.token("/");
+                        self.token(&mask.to_string());
+                    }
+                    self.token(";");
+                }
+                self.token("}");
+            }
+            Declaration::Backend(name, properties) => {
+                self.token("backend");
+                self.token(name.as_str());
+                self.properties(properties);
+            }
+            Declaration::BackendNone(name) => {
+                self.token("backend");
+                self.token(name.as_str());
+                self.token("none");
+                self.token(";");
+            }
+            Declaration::Probe(name, properties) => {
+                self.token("probe");
+                self.token(name.as_str());
+                self.properties(properties);
+            }
+            Declaration::Sub(name, body) => {
+                self.token("sub");
+                self.token(name.as_str());
+                self.block(body);
+            }
+        }
+    }
+
+    fn properties(&mut self, properties: &[(Name, Expr)]) {
+        self.token("{");
+        for (name, value) in properties {
+            self.token(&format!(".{}", name.as_str().replace('.', "_")));
+            self.token("=");
+            self.expr(value);
+            self.token(";");
+        }
+        self.token("}");
+    }
+
+    fn block(&mut self, body: &[Statement]) {
+        self.token("{");
+        for statement in body {
+            self.statement(statement);
+        }
+        self.token("}");
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Set(name, op, expr) => {
+                self.token("set");
+                self.token(name.as_str());
+                self.token(op.as_str());
+                self.expr(expr);
+                self.token(";");
+            }
+            Statement::Unset(name) => {
+                self.token("unset");
+                self.token(name.as_str());
+                self.token(";");
+            }
+            Statement::Call(name) => {
+                self.token("call");
+                self.token(name.as_str());
+                self.token(";");
+            }
+            Statement::IdentCall(name, args) => {
+                self.call(name, args);
+                self.token(";");
+            }
+            Statement::Return(name, args) => {
+                self.token("return");
+                self.token("(");
+                self.token(name.as_str());
+                if let Some(args) = args {
+                    self.token("(");
+                    self.list(args);
+                    self.token(")");
+                }
+                self.token(")");
+                self.token(";");
+            }
+            Statement::If {
+                condition,
+                body,
+                elseifs,
+                else_body,
+            } => {
+                self.token("if");
+                self.condition(condition);
+                self.block(body);
+                for (condition, body) in elseifs {
+                    self.token("elseif");
+                    self.condition(condition);
+                    self.block(body);
+                }
+                if let Some(body) = else_body {
+                    self.token("else");
+                    self.block(body);
+                }
+            }
+        }
+    }
+
+    fn condition(&mut self, condition: &Expr) {
+        self.token("(");
+        self.expr(condition);
+        self.token(")");
+    }
+
+    fn call(&mut self, name: &Name, args: &[Expr]) {
+        self.token(name.as_str());
+        self.token("(");
+        self.list(args);
+        self.token(")");
+    }
+
+    fn list(&mut self, items: &[Expr]) {
+        for (idx, item) in items.iter().enumerate() {
+            if idx > 0 {
+                self.token(",");
+            }
+            self.expr(item);
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Ident(name) => self.token(name.as_str()),
+            Expr::Str(s) => self.token(s.as_str()),
+            Expr::Number(n) => self.token(&n.to_string()),
+            Expr::Duration(n) => self.token(&format!("{n}s")),
+            Expr::Bool(b) => self.token(if *b { "true" } else { "false" }),
+            Expr::Call(name, args) => self.call(name, args),
+            Expr::Neg(expr) => {
+                self.token("!");
+                self.expr(expr);
+            }
+            Expr::Binary(left, op, right) => {
+                self.expr(left);
+                self.token(op.as_str());
+                self.expr(right);
+            }
+            Expr::Parenthesized(expr) => {
+                self.token("(");
+                self.expr(expr);
+                self.token(")");
+            }
+        }
+    }
+}
+
+impl fmt::Display for File {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut renderer = Renderer {
+            out: String::new(),
+            trivia: &self.trivia,
+            next_trivia: 0,
+        };
+        renderer.token("vcl");
+        renderer.token("4.1");
+        renderer.token(";");
+        for declaration in &self.declarations {
+            renderer.declaration(declaration);
+        }
+        f.write_str(&renderer.out)?;
+        f.write_str("\n")
+    }
+}
@@ -0,0 +1,29 @@
+pub mod generate;
+
+use vcl_formatter::{parser::FragmentKind, process_vcl, style::Style, verify};
+
+const FRAGMENT: Option<FragmentKind> = Some(FragmentKind::Auto);
+
+/// Formats `source` twice and panics if the formatter changed the code or the second pass
+/// changed the output again. Returns `false` if the source was rejected as invalid VCL.
+pub fn check_formatting(source: &str) -> bool {
+    let style = Style::default();
+    let mut first = vec![];
+    if process_vcl(source, &style, FRAGMENT, 0, &[], &mut first).is_err() {
+        return false;
+    }
+    let first = std::str::from_utf8(&first).expect("formatted output is not UTF-8");
+    if let Err(e) = verify::verify(source, first, FRAGMENT, &[]) {
+        panic!("{e:?}\n--- input\n{source}\n--- output\n{first}");
+    }
+
+    let mut second = vec![];
+    if let Err(e) = process_vcl(first, &style, FRAGMENT, 0, &[], &mut second) {
+        panic!("output was rejected: {e:?}\n--- output\n{first}");
+    }
+    let second = std::str::from_utf8(&second).expect("formatted output is not UTF-8");
+    if let Err(e) = verify::verify_idempotent(first, second) {
+        panic!("{e:?}\n--- first\n{first}\n--- second\n{second}");
+    }
+    true
+}
@@ -0,0 +1,285 @@
+use std::{borrow::Cow, cmp::Ordering, net::IpAddr};
+
+use crate::{
+    ast::{AclEntry, Fragment, TopLevelDeclaration},
+    ast_emitter::kept_as_written,
+    error::E,
+    lexer::{self, same_line_trivia_len, string_value, Delimiters, Token},
+    parser::{self, FragmentKind},
+};
+
+/// Network matched by an ACL entry: an address and the number of leading bits that must match.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Network {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Network {
+    /// Whether every address of `other` is also in this network.
+    pub fn contains(&self, other: &Network) -> bool {
+        if self.prefix > other.prefix {
+            return false;
+        }
+        match (self.addr, other.addr) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                prefix_eq(&a.octets(), &b.octets(), self.prefix.into())
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                prefix_eq(&a.octets(), &b.octets(), self.prefix.into())
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], bits: usize) -> bool {
+    let bytes = bits / 8;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    let rest = bits % 8;
+    rest == 0 || (a[bytes] ^ b[bytes]) >> (8 - rest) == 0
+}
+
+/// Parses the address and mask of an ACL entry. Entries that do not look like an address are
+/// host names, which Varnish resolves when loading the VCL, and give `None`.
+pub fn network(entry: &AclEntry) -> Result<Option<Network>, E> {
+    let value = string_value(entry.value.content);
+    let looks_like_address = value.contains(':')
+        || (!value.is_empty() && value.chars().all(|c| c == '.' || c.is_ascii_digit()));
+    if !looks_like_address {
+        return Ok(None);
+    }
+    let addr: IpAddr = value.parse().map_err(|_| E::InvalidAclAddress {
+        address: value.to_string(),
+        line: entry.value.line,
+        column: entry.value.column,
+    })?;
+    let max = match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    let prefix = match &entry.mask {
+        None => max,
+        Some(m) => m
+            .mask
+            .content
+            .parse()
+            .ok()
+            .filter(|p| *p <= max)
+            .ok_or_else(|| E::AclMaskOutOfRange {
+                mask: m.mask.content.to_string(),
+                max,
+                line: m.mask.line,
+                column: m.mask.column,
+            })?,
+    };
+    Ok(Some(Network { addr, prefix }))
+}
+
+/// Address of an ACL entry as it is printed when normalizing: IPv6 addresses are compressed and
+/// lowercase (RFC 5952), host names are kept as written.
+fn canonical_value(entry: &AclEntry, network: Option<Network>) -> String {
+    match network {
+        Some(Network {
+            addr: IpAddr::V6(a),
+            ..
+        }) => format!("\"{a}\""),
+        _ => entry.value.content.to_string(),
+    }
+}
+
+/// Order of sorted ACL entries: IPv4 networks, IPv6 networks, then host names.
+fn compare(a: (&AclEntry, Option<Network>), b: (&AclEntry, Option<Network>)) -> Ordering {
+    match (a.1, b.1) {
+        (Some(x), Some(y)) => x.cmp(&y),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => string_value(a.0.value.content).cmp(string_value(b.0.value.content)),
+    }
+}
+
+/// Problem found in an ACL that does not stop it from working.
+#[derive(Debug)]
+pub enum AclWarning {
+    Duplicate {
+        acl: String,
+        entry: String,
+        line: usize,
+        first_line: usize,
+    },
+    Covered {
+        acl: String,
+        entry: String,
+        line: usize,
+        by: String,
+        by_line: usize,
+    },
+}
+
+impl std::fmt::Display for AclWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AclWarning::Duplicate {
+                acl,
+                entry,
+                line,
+                first_line,
+            } => {
+                f.write_fmt(format_args!(
+                    "ACL {acl} lists {entry} again (line={line}, first on line {first_line})"
+                ))?;
+                Ok(())
+            }
+            AclWarning::Covered {
+                acl,
+                entry,
+                line,
+                by,
+                by_line,
+            } => {
+                f.write_fmt(format_args!(
+                    "ACL {acl} entry {entry} is already covered by {by} (line={line}, covered by line {by_line})"
+                ))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Finds duplicate entries and entries covered by a broader network in the ACLs of `source`.
+pub fn check(
+    source: &str,
+    fragment: Option<FragmentKind>,
+    placeholders: &[Delimiters],
+) -> Result<Vec<AclWarning>, E> {
+    let tokens = lexer::lex_with_placeholders(source, placeholders)?;
+    let declarations = declarations(&tokens, fragment)?;
+
+    let mut warnings = vec![];
+    for decl in &declarations {
+        let TopLevelDeclaration::Acl { name, entries, .. } = decl else {
+            continue;
+        };
+        let mut networks = vec![];
+        for entry in entries {
+            if let Some(n) = network(entry)? {
+                networks.push((entry, n));
+            }
+        }
+        for (idx, (entry, n)) in networks.iter().enumerate() {
+            let same = |other: &Network| other.contains(n) && n.contains(other);
+            let duplicate = networks[..idx].iter().find(|(_, other)| same(other));
+            let broader = networks
+                .iter()
+                .find(|(_, other)| other.contains(n) && !same(other));
+            if let Some((first, _)) = duplicate {
+                warnings.push(AclWarning::Duplicate {
+                    acl: name.content.to_string(),
+                    entry: describe(entry),
+                    line: entry.value.line,
+                    first_line: first.value.line,
+                });
+            } else if let Some((by, _)) = broader {
+                warnings.push(AclWarning::Covered {
+                    acl: name.content.to_string(),
+                    entry: describe(entry),
+                    line: entry.value.line,
+                    by: describe(by),
+                    by_line: by.value.line,
+                });
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+fn describe(entry: &AclEntry) -> String {
+    match &entry.mask {
+        Some(m) => format!("{}/{}", entry.value.content, m.mask.content),
+        None => entry.value.content.to_string(),
+    }
+}
+
+/// Rewrites the ACLs of `source` with IPv6 addresses in canonical form and, if `sort` is set,
+/// entries sorted by network. Comments before an entry and at the end of its line move with it.
+/// ACLs that `vclfmt` directives keep as written are left alone. Fails on addresses that do not
+/// parse and masks that are out of range.
+pub fn normalize<'s>(
+    source: &'s str,
+    fragment: Option<FragmentKind>,
+    placeholders: &[Delimiters],
+    sort: bool,
+) -> Result<Cow<'s, str>, E> {
+    let tokens = lexer::lex_with_placeholders(source, placeholders)?;
+    let declarations = declarations(&tokens, fragment)?;
+    let kept = kept_as_written(&declarations, TopLevelDeclaration::first_token)?;
+
+    let mut out = String::with_capacity(source.len());
+    let mut copied = 0;
+    for (decl, kept) in declarations.iter().zip(kept) {
+        let TopLevelDeclaration::Acl {
+            entries, rbrace, ..
+        } = decl
+        else {
+            continue;
+        };
+        if kept {
+            continue;
+        }
+        let mut chunks = vec![];
+        for (idx, entry) in entries.iter().enumerate() {
+            let network = network(entry)?;
+            let value = &entry.value;
+            let next = entries.get(idx + 1).map_or(rbrace, |e| &e.value);
+            let start =
+                value.offset - value.pre_trivia.len() + same_line_trivia_len(value.pre_trivia);
+            let end = next.offset - next.pre_trivia.len() + same_line_trivia_len(next.pre_trivia);
+            let mut text = source[start..value.offset].to_string();
+            text.push_str(&canonical_value(entry, network));
+            text.push_str(&source[value.offset + value.content.len()..end]);
+            chunks.push((start, entry, network, text));
+        }
+        let Some(&(start, ..)) = chunks.first() else {
+            continue;
+        };
+        if sort {
+            chunks.sort_by(|a, b| compare((a.1, a.2), (b.1, b.2)));
+        }
+
+        out.push_str(&source[copied..start]);
+        for (_, _, _, text) in chunks {
+            // A comment at the end of the previous entry's line must not swallow this one. Blank
+            // lines separate groups of entries, which sorting does not keep.
+            if sort {
+                out.push('\n');
+                out.push_str(text.trim_start());
+            } else {
+                if !text.starts_with(['\n', '\r']) {
+                    out.push('\n');
+                }
+                out.push_str(&text);
+            }
+        }
+        copied = rbrace.offset - rbrace.pre_trivia.len() + same_line_trivia_len(rbrace.pre_trivia);
+        if !source[copied..].starts_with(['\n', '\r']) {
+            out.push('\n');
+        }
+    }
+    out.push_str(&source[copied..]);
+    Ok(Cow::Owned(out))
+}
+
+fn declarations<'a>(
+    tokens: &'a [Token<'a>],
+    fragment: Option<FragmentKind>,
+) -> Result<Vec<TopLevelDeclaration<'a>>, E> {
+    match fragment {
+        Some(kind) => match parser::parse_fragment(tokens, kind)? {
+            Fragment::TopLevel(sf) => Ok(sf.declarations),
+            _ => Ok(vec![]),
+        },
+        None => Ok(parser::parse(tokens)?.declarations),
+    }
+}
@@ -1,11 +1,75 @@
-use crate::lexer::TokenData;
+use serde::{ser::SerializeSeq, Serialize, Serializer};
 
+use crate::lexer::{string_value, TokenData};
+
+#[derive(Debug, Serialize)]
 pub struct SourceFile<'a> {
     pub declarations: Vec<TopLevelDeclaration<'a>>,
     pub eof: TokenData<'a>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Fragment<'a> {
+    TopLevel(SourceFile<'a>),
+    Statements {
+        statements: Vec<Statement<'a>>,
+        eof: TokenData<'a>,
+    },
+    Expression {
+        expr: Expression<'a>,
+        eof: TokenData<'a>,
+    },
+}
+
+impl<'a> Fragment<'a> {
+    /// Returns all includes, both top-level and nested inside subs, in source order.
+    pub fn includes(&self) -> Vec<&IncludeData<'a>> {
+        let mut result = vec![];
+        match self {
+            Fragment::TopLevel(sf) => {
+                for td in &sf.declarations {
+                    match td {
+                        TopLevelDeclaration::Include(i) => result.push(i),
+                        TopLevelDeclaration::Sub { statements, .. } => {
+                            statement_includes(statements, &mut result)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Fragment::Statements { statements, .. } => statement_includes(statements, &mut result),
+            Fragment::Expression { .. } => {}
+        }
+        result
+    }
+}
+
+fn statement_includes<'s, 'a>(statements: &'s [Statement<'a>], out: &mut Vec<&'s IncludeData<'a>>) {
+    for st in statements {
+        match st {
+            Statement::Include(i) => out.push(i),
+            Statement::If {
+                body,
+                elseifs,
+                else_st,
+                ..
+            } => {
+                statement_includes(body, out);
+                for ei in elseifs {
+                    statement_includes(&ei.body, out);
+                }
+                if let Some(e) = else_st {
+                    statement_includes(&e.body, out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum TopLevelDeclaration<'a> {
     VclVersion {
         vcl: TokenData<'a>,
@@ -41,9 +105,44 @@ pub enum TopLevelDeclaration<'a> {
         statements: Vec<Statement<'a>>,
         rbrace: TokenData<'a>,
     },
+    Placeholder {
+        token: TokenData<'a>,
+        semi: Option<TokenData<'a>>,
+    },
 }
 
-#[derive(Debug)]
+impl<'a> TopLevelDeclaration<'a> {
+    pub fn first_token(&self) -> &TokenData<'a> {
+        match self {
+            TopLevelDeclaration::VclVersion { vcl, .. } => vcl,
+            TopLevelDeclaration::Import { import, .. } => import,
+            TopLevelDeclaration::Include(i) => &i.include,
+            TopLevelDeclaration::Acl { acl, .. } => acl,
+            TopLevelDeclaration::Backend(BackendData::Defined { backend, .. })
+            | TopLevelDeclaration::Backend(BackendData::None { backend, .. }) => backend,
+            TopLevelDeclaration::Probe { probe, .. } => probe,
+            TopLevelDeclaration::Sub { sub, .. } => sub,
+            TopLevelDeclaration::Placeholder { token, .. } => token,
+        }
+    }
+
+    pub fn first_token_mut(&mut self) -> &mut TokenData<'a> {
+        match self {
+            TopLevelDeclaration::VclVersion { vcl, .. } => vcl,
+            TopLevelDeclaration::Import { import, .. } => import,
+            TopLevelDeclaration::Include(i) => &mut i.include,
+            TopLevelDeclaration::Acl { acl, .. } => acl,
+            TopLevelDeclaration::Backend(BackendData::Defined { backend, .. })
+            | TopLevelDeclaration::Backend(BackendData::None { backend, .. }) => backend,
+            TopLevelDeclaration::Probe { probe, .. } => probe,
+            TopLevelDeclaration::Sub { sub, .. } => sub,
+            TopLevelDeclaration::Placeholder { token, .. } => token,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "definition", rename_all = "snake_case")]
 pub enum BackendData<'a> {
     Defined {
         backend: TokenData<'a>,
@@ -60,40 +159,47 @@ pub enum BackendData<'a> {
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct IncludeData<'a> {
     pub include: TokenData<'a>,
     pub name: TokenData<'a>,
     pub semi: TokenData<'a>,
 }
 
-#[derive(Debug)]
+impl<'a> IncludeData<'a> {
+    pub fn path(&self) -> &'a str {
+        string_value(self.name.content)
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct FromData<'a> {
     pub from: TokenData<'a>,
     pub value: TokenData<'a>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct AclEntry<'a> {
     pub value: TokenData<'a>,
     pub mask: Option<MaskData<'a>>,
     pub semi: TokenData<'a>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct MaskData<'a> {
     pub op: TokenData<'a>,
     pub mask: TokenData<'a>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct BackendProperty<'a> {
     pub name: TokenData<'a>,
     pub op: TokenData<'a>,
     pub value: BackendValue<'a>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum BackendValue<'a> {
     Expression {
         expr: Expression<'a>,
@@ -110,7 +216,7 @@ pub enum BackendValue<'a> {
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct ElseIfStatement<'a> {
     pub elseif: Vec<TokenData<'a>>,
     pub lparen: TokenData<'a>,
@@ -121,15 +227,16 @@ pub struct ElseIfStatement<'a> {
     pub rbrace: TokenData<'a>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct ElseStatement<'a> {
+    #[serde(rename = "else")]
     pub else_t: TokenData<'a>,
     pub lbrace: TokenData<'a>,
     pub body: Vec<Statement<'a>>,
     pub rbrace: TokenData<'a>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct IdentCallExpression<'a> {
     pub name: TokenData<'a>,
     pub lparen: TokenData<'a>,
@@ -137,14 +244,15 @@ pub struct IdentCallExpression<'a> {
     pub rparen: TokenData<'a>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct ReturnArgs<'a> {
     pub lparen: TokenData<'a>,
     pub args: DelimitedList<Expression<'a>, TokenData<'a>>,
     pub rparen: TokenData<'a>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum Statement<'a> {
     Set {
         set: TokenData<'a>,
@@ -168,6 +276,7 @@ pub enum Statement<'a> {
         semi: TokenData<'a>,
     },
     If {
+        #[serde(rename = "if")]
         if_t: TokenData<'a>,
         lparen: TokenData<'a>,
         condition: Expression<'a>,
@@ -176,9 +285,11 @@ pub enum Statement<'a> {
         body: Vec<Statement<'a>>,
         rbrace: TokenData<'a>,
         elseifs: Vec<ElseIfStatement<'a>>,
+        #[serde(rename = "else")]
         else_st: Option<ElseStatement<'a>>,
     },
     Return {
+        #[serde(rename = "return")]
         return_t: TokenData<'a>,
         lparen: TokenData<'a>,
         name: TokenData<'a>,
@@ -194,9 +305,30 @@ pub enum Statement<'a> {
         semi: TokenData<'a>,
     },
     Include(IncludeData<'a>),
+    Placeholder {
+        token: TokenData<'a>,
+        semi: Option<TokenData<'a>>,
+    },
 }
 
-#[derive(Debug)]
+impl<'a> Statement<'a> {
+    pub fn first_token(&self) -> &TokenData<'a> {
+        match self {
+            Statement::Set { set, .. } => set,
+            Statement::Unset { unset, .. } => unset,
+            Statement::Call { call, .. } => call,
+            Statement::IdentCall { expr, .. } => &expr.name,
+            Statement::If { if_t, .. } => if_t,
+            Statement::Return { return_t, .. } => return_t,
+            Statement::New { new, .. } => new,
+            Statement::Include(i) => &i.include,
+            Statement::Placeholder { token, .. } => token,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum Expression<'a> {
     Ident(TokenData<'a>),
     Literal(TokenData<'a>),
@@ -217,7 +349,30 @@ pub enum Expression<'a> {
     },
 }
 
-#[derive(Debug)]
+impl<'a> Expression<'a> {
+    pub fn first_token(&self) -> &TokenData<'a> {
+        match self {
+            Expression::Ident(t) | Expression::Literal(t) => t,
+            Expression::Neg { op, .. } => op,
+            Expression::Binary { left, .. } => left.first_token(),
+            Expression::IdentCall(c) => &c.name,
+            Expression::Parenthesized { lparen, .. } => lparen,
+        }
+    }
+
+    pub fn last_token(&self) -> &TokenData<'a> {
+        match self {
+            Expression::Ident(t) | Expression::Literal(t) => t,
+            Expression::Neg { expr, .. } => expr.last_token(),
+            Expression::Binary { right, .. } => right.last_token(),
+            Expression::IdentCall(c) => &c.rparen,
+            Expression::Parenthesized { rparen, .. } => rparen,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "arg", rename_all = "snake_case")]
 pub enum FunctionCallArg<'a> {
     Named {
         name: TokenData<'a>,
@@ -266,18 +421,7 @@ impl<'a, Item, Separator> Iterator for DelimitedListIterator<'a, Item, Separator
                 None
             }
             DelimitedList::WithItems { pairs, last_item } => {
-                let inner_iter = {
-                    match &mut self.inner_iter {
-                        Some(i) => i,
-                        None => {
-                            self.inner_iter = Some(pairs.iter());
-                            match &mut self.inner_iter {
-                                Some(i) => i,
-                                None => panic!("should never happen, just set it to Some"),
-                            }
-                        }
-                    }
-                };
+                let inner_iter = self.inner_iter.get_or_insert_with(|| pairs.iter());
                 let inner_next = inner_iter.next();
                 match inner_next {
                     Some(i) => Some((&i.0, Some(&i.1))),
@@ -290,3 +434,21 @@ impl<'a, Item, Separator> Iterator for DelimitedListIterator<'a, Item, Separator
         }
     }
 }
+
+/// Serialized as a sequence of `{"item": ..., "separator": ...}` objects, the last separator
+/// being `null`.
+impl<Item: Serialize, Separator: Serialize> Serialize for DelimitedList<Item, Separator> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Entry<'l, Item, Separator> {
+            item: &'l Item,
+            separator: Option<&'l Separator>,
+        }
+
+        let mut seq = serializer.serialize_seq(None)?;
+        for (item, separator) in self.iter() {
+            seq.serialize_element(&Entry { item, separator })?;
+        }
+        seq.end()
+    }
+}
@@ -2,33 +2,330 @@ use std::io::Write;
 
 use crate::{
     ast::*,
-    emitter::Emitter,
-    error::R,
+    emitter::{Block, Emitter},
+    error::{E, R},
     lexer::{lex_trivia, TokenData, TriviaToken},
+    style::{BreakPosition, Style},
 };
 
+/// Provides contents of included files, so that includes can be inlined while emitting.
+pub trait IncludeExpander {
+    /// Returns the parsed included file together with its path, if it should be inlined.
+    fn expand<'s>(&'s self, include: &IncludeData) -> Option<(&'s Fragment<'s>, &'s str)>;
+}
+
+/// Formatter directive in a comment, e.g. `# vclfmt: off`.
+#[derive(Copy, Clone, PartialEq)]
+pub(crate) enum Directive {
+    /// Keep everything up to the next `on` in the same block as written.
+    Off,
+    On,
+    /// Keep the next declaration or statement as written.
+    Skip,
+}
+
 pub struct AstEmitter<'a> {
     e: crate::emitter::StandardEmitter<'a>,
+    style: Style,
+    sources: Vec<&'a str>,
+    expander: Option<&'a dyn IncludeExpander>,
+    markers: bool,
+    origins: Vec<String>,
+    last_marked_origin: Option<String>,
+    pending_marker: Option<String>,
+    /// Spaces to add before the operator of the next `set` statement, to align it.
+    set_padding: usize,
+    /// Set when the comments before the next token of an expression were already emitted.
+    expression_trivia_emitted: bool,
 }
 
 impl<'a> AstEmitter<'a> {
-    pub fn new(writer: &'a mut dyn Write, indent: usize) -> Self {
-        let e = crate::emitter::StandardEmitter::new(writer, indent);
-        Self { e }
+    pub fn new(writer: &'a mut dyn Write, style: &Style, base_indent: usize) -> Self {
+        let e = crate::emitter::StandardEmitter::new(writer, style.clone(), base_indent);
+        Self {
+            e,
+            style: style.clone(),
+            sources: vec![],
+            expander: None,
+            markers: false,
+            origins: vec![],
+            last_marked_origin: None,
+            pending_marker: None,
+            set_padding: 0,
+            expression_trivia_emitted: false,
+        }
+    }
+
+    /// Makes `source`, which syntax trees passed to this emitter were parsed from, available for
+    /// code that `vclfmt` directives exclude from formatting. Without it, directives are ignored.
+    /// Can be given several times if trees come from several sources.
+    pub fn with_source(mut self, source: &'a str) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    /// Inlines includes using `expander`, optionally marking where inlined code came from.
+    pub fn with_includes(
+        mut self,
+        expander: &'a dyn IncludeExpander,
+        origin: &str,
+        markers: bool,
+    ) -> Self {
+        self.expander = Some(expander);
+        self.markers = markers;
+        self.origins = vec![origin.to_string()];
+        self
     }
 
     pub fn emit(&mut self, sf: &SourceFile) -> R {
-        for td in &sf.declarations {
-            self.emit_toplevel_declaration(td)?;
-        }
+        self.emit_declarations(&sf.declarations, &sf.eof)?;
+        self.emit_trailing_trivia(&sf.eof)?;
         self.e.file_end()?;
         Ok(())
     }
 
+    pub fn emit_fragment(&mut self, f: &Fragment) -> R {
+        match f {
+            Fragment::TopLevel(sf) => self.emit(sf)?,
+            Fragment::Statements { statements, eof } => {
+                self.emit_statements(statements, eof)?;
+                self.emit_trailing_trivia(eof)?;
+                self.e.file_end()?;
+            }
+            Fragment::Expression { expr, eof } => {
+                self.emit_expression_comments(expr)?;
+                // Hoisted comments end their line; newlines before the expression must not add
+                // a blank line after them.
+                self.e.newlines(1)?;
+                self.emit_expression(expr, false)?;
+                self.emit_trailing_trivia(eof)?;
+                self.e.file_end()?;
+            }
+        };
+        Ok(())
+    }
+
+    fn emit_declarations(&mut self, declarations: &[TopLevelDeclaration], end: &TokenData) -> R {
+        self.emit_items(
+            declarations,
+            end,
+            TopLevelDeclaration::first_token,
+            |d| matches!(d, TopLevelDeclaration::Include(_)),
+            Self::emit_toplevel_declaration,
+            Self::blank_lines_between_declarations,
+            &[],
+        )
+    }
+
+    fn emit_statements(&mut self, statements: &[Statement], end: &TokenData) -> R {
+        let assignments: Vec<_> = statements
+            .iter()
+            .map(|st| match st {
+                Statement::Set { set, ident, op, .. } => Some((set, ident, op)),
+                _ => None,
+            })
+            .collect();
+        let paddings = self.alignment(&assignments)?;
+        self.emit_items(
+            statements,
+            end,
+            Statement::first_token,
+            |st| matches!(st, Statement::Include(_)),
+            Self::emit_statement,
+            Self::blank_lines_between_statements,
+            &paddings,
+        )
+    }
+
+    fn emit_backend_properties(&mut self, properties: &[BackendProperty]) -> R {
+        let assignments: Vec<_> = properties
+            .iter()
+            .map(|p| Some((&p.name, &p.name, &p.op)))
+            .collect();
+        let paddings = self.alignment(&assignments)?;
+        for (prop, padding) in properties.iter().zip(paddings) {
+            self.emit_backend_property(&prop.name, &prop.op, &prop.value, padding)?;
+        }
+        Ok(())
+    }
+
+    /// Number of spaces to put after each assignment target so that the `=` of consecutive
+    /// assignments line up, including the one at the end of operators such as `+=`. Assignments
+    /// are given by their first token, their target and their operator; a blank line, a comment
+    /// on its own line or a `None` entry ends a run.
+    fn alignment(
+        &self,
+        assignments: &[Option<(&TokenData, &TokenData, &TokenData)>],
+    ) -> Result<Vec<usize>, E> {
+        let mut paddings = vec![0; assignments.len()];
+        if !self.style.align_assignments {
+            return Ok(paddings);
+        }
+
+        let mut runs: Vec<Vec<usize>> = vec![];
+        for (idx, assignment) in assignments.iter().enumerate() {
+            let Some((first, ..)) = assignment else {
+                continue;
+            };
+            let continues_run = idx > 0 && assignments[idx - 1].is_some() && !separated(first)?;
+            match runs.last_mut() {
+                Some(run) if continues_run => run.push(idx),
+                _ => runs.push(vec![idx]),
+            }
+        }
+
+        let width = |idx: usize| {
+            assignments[idx].map_or(0, |(_, target, op)| {
+                target.content.chars().count() + op.content.chars().count()
+            })
+        };
+        for run in runs {
+            let max_width = run.iter().map(|&idx| width(idx)).max().unwrap_or(0);
+            for idx in run {
+                paddings[idx] = max_width - width(idx);
+            }
+        }
+        Ok(paddings)
+    }
+
+    fn blank_lines_between_declarations(
+        &self,
+        prev: &TopLevelDeclaration,
+        next: &TopLevelDeclaration,
+    ) -> usize {
+        match (prev, next) {
+            (TopLevelDeclaration::Import { .. }, TopLevelDeclaration::Import { .. })
+            | (TopLevelDeclaration::Include(_), TopLevelDeclaration::Include(_)) => 0,
+            _ => self.style.blank_lines_between_declarations,
+        }
+    }
+
+    fn blank_lines_between_statements(&self, prev: &Statement, next: &Statement) -> usize {
+        let is_if = |st: &Statement| matches!(st, Statement::If { .. });
+        usize::from(self.style.blank_lines_around_if && (is_if(prev) || is_if(next)))
+    }
+
+    /// Emits a list of declarations or statements followed by `end`, keeping items excluded by
+    /// `vclfmt` directives as written. Includes are inlined even when excluded, so that a bundle
+    /// never refers to other files.
+    #[allow(clippy::too_many_arguments)]
+    fn emit_items<'t, T>(
+        &mut self,
+        items: &'t [T],
+        end: &'t TokenData<'t>,
+        first_token: fn(&'t T) -> &'t TokenData<'t>,
+        is_include: fn(&T) -> bool,
+        emit_item: fn(&mut Self, &T) -> R,
+        blank_lines_between: fn(&Self, &T, &T) -> usize,
+        set_paddings: &[usize],
+    ) -> R {
+        let mut idx = 0;
+        while idx < items.len() {
+            if let Some(prev) = idx.checked_sub(1).map(|i| &items[i]) {
+                let blank_lines = blank_lines_between(self, prev, &items[idx]);
+                self.e.hint_blank_lines(blank_lines);
+            }
+            let first = first_token(&items[idx]);
+            self.mark_origin(first);
+            let unformatted_until = match directives(first)?.last() {
+                Some(Directive::Skip) => Some(idx + 1),
+                Some(Directive::Off) => {
+                    let mut until = items.len();
+                    for (i, item) in items.iter().enumerate().skip(idx + 1) {
+                        if directives(first_token(item))?.contains(&Directive::On) {
+                            until = i;
+                            break;
+                        }
+                    }
+                    Some(until)
+                }
+                _ => None,
+            };
+            let next = unformatted_until.map(|i| items.get(i).map_or(end, first_token));
+            match unformatted_until.zip(next.and_then(|next| self.original_text(first, next))) {
+                Some((until, _)) if self.expander.is_some() => {
+                    while idx < until {
+                        let include = (idx..until).find(|&i| is_include(&items[i]));
+                        let verbatim_until = include.unwrap_or(until);
+                        if verbatim_until > idx {
+                            let first = first_token(&items[idx]);
+                            let next = items.get(verbatim_until).map_or(end, first_token);
+                            if let Some(text) = self.original_text(first, next) {
+                                self.emit_all_trivia(first)?;
+                                self.e.verbatim(text)?;
+                            }
+                        }
+                        match include {
+                            Some(i) => {
+                                self.set_padding = 0;
+                                emit_item(self, &items[i])?;
+                                idx = i + 1;
+                            }
+                            None => idx = until,
+                        }
+                    }
+                }
+                Some((until, text)) => {
+                    self.emit_all_trivia(first)?;
+                    self.e.verbatim(text)?;
+                    idx = until;
+                }
+                None => {
+                    self.set_padding = set_paddings.get(idx).copied().unwrap_or(0);
+                    emit_item(self, &items[idx])?;
+                    idx += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Source text from `first` up to the trivia before `next`, if both come from a known source.
+    fn original_text(&self, first: &TokenData, next: &TokenData) -> Option<&'a str> {
+        let source = self
+            .sources
+            .iter()
+            .find(|s| is_token_of(s, first) && is_token_of(s, next))?;
+        source.get(first.offset..next.offset.checked_sub(next.pre_trivia.len())?)
+    }
+
+    /// Schedules a `# from:` marker if the next item comes from a different file than the last one.
+    fn mark_origin(&mut self, first_token: &TokenData) {
+        if !self.markers {
+            return;
+        }
+        if let Some(origin) = self.origins.last() {
+            if self.last_marked_origin.as_ref() != Some(origin) {
+                self.pending_marker = Some(format!("# from: {origin}:{}", first_token.line));
+                self.last_marked_origin = Some(origin.clone());
+            }
+        }
+    }
+
     fn emit_all_trivia(&mut self, token: &TokenData) -> R {
         let tokens = lex_trivia(token.pre_trivia)?;
+        self.emit_trivia_tokens(&tokens)?;
+        if let Some(marker) = self.pending_marker.take() {
+            self.e.newlines(1)?;
+            self.e.comment(&marker)?;
+        }
+        Ok(())
+    }
+
+    fn emit_trailing_trivia(&mut self, eof: &TokenData) -> R {
+        let tokens = lex_trivia(eof.pre_trivia)?;
+        let last_comment = tokens
+            .iter()
+            .rposition(|t| !matches!(t, TriviaToken::Newline));
+        if let Some(last) = last_comment {
+            self.emit_trivia_tokens(&tokens[..=last])?;
+        }
+        Ok(())
+    }
+
+    fn emit_trivia_tokens(&mut self, tokens: &[TriviaToken]) -> R {
         let mut curr_lines = 0;
-        for t in &tokens {
+        for t in tokens {
             match t {
                 TriviaToken::LineComment(s)
                 | TriviaToken::MultilineComment(s)
@@ -84,7 +381,7 @@ impl<'a> AstEmitter<'a> {
                 from,
                 semi,
             } => self.emit_import(import, name, from.as_ref(), semi)?,
-            TopLevelDeclaration::Include(i) => self.emit_include(i)?,
+            TopLevelDeclaration::Include(i) => self.emit_include(i, true)?,
             TopLevelDeclaration::Acl {
                 acl,
                 name,
@@ -107,11 +404,30 @@ impl<'a> AstEmitter<'a> {
                 statements,
                 rbrace,
             } => self.emit_sub(sub, name, lbrace, statements, rbrace)?,
+            TopLevelDeclaration::Placeholder { token, semi } => {
+                self.emit_placeholder(token, semi.as_ref())?
+            }
         };
 
         Ok(())
     }
 
+    fn emit_placeholder(&mut self, token: &TokenData, semi: Option<&TokenData>) -> R {
+        self.emit_all_trivia(token)?;
+        if let Some(semi) = semi {
+            self.emit_comments(semi)?;
+        }
+
+        self.e.placeholder(token.content)?;
+        match semi {
+            Some(_) => self.e.semicolon()?,
+            // Template tags such as `{% if %}` stand on their own line.
+            None => self.e.hint_line_end(),
+        }
+
+        Ok(())
+    }
+
     fn emit_vcl_version(&mut self, vcl: &TokenData, number: &TokenData, semi: &TokenData) -> R {
         self.emit_all_trivia(vcl)?;
         self.emit_comments(number)?;
@@ -150,7 +466,11 @@ impl<'a> AstEmitter<'a> {
         Ok(())
     }
 
-    fn emit_include(&mut self, inc: &IncludeData) -> R {
+    fn emit_include(&mut self, inc: &IncludeData, top_level: bool) -> R {
+        if let Some((fragment, origin)) = self.expander.and_then(|x| x.expand(inc)) {
+            return self.emit_inlined(inc, fragment, origin, top_level);
+        }
+
         self.emit_all_trivia(&inc.include)?;
         self.emit_comments(&inc.name)?;
         self.emit_comments(&inc.semi)?;
@@ -162,6 +482,49 @@ impl<'a> AstEmitter<'a> {
         Ok(())
     }
 
+    fn emit_inlined(
+        &mut self,
+        inc: &IncludeData,
+        fragment: &Fragment,
+        origin: &str,
+        top_level: bool,
+    ) -> R {
+        self.pending_marker = None;
+        self.emit_all_trivia(&inc.include)?;
+        self.emit_comments(&inc.name)?;
+        self.emit_comments(&inc.semi)?;
+
+        self.origins.push(origin.to_string());
+        match fragment {
+            Fragment::TopLevel(sf) if top_level || sf.declarations.is_empty() => {
+                self.emit_declarations(&sf.declarations, &sf.eof)?;
+                self.emit_trailing_trivia(&sf.eof)?;
+            }
+            Fragment::Statements { statements, eof } if !top_level => {
+                self.emit_statements(statements, eof)?;
+                self.emit_trailing_trivia(eof)?;
+            }
+            _ => {
+                let error = E::IncompatibleInclude {
+                    name: inc.path().to_string(),
+                    line: inc.include.line,
+                    column: inc.include.column,
+                };
+                self.origins.pop();
+                return Err(match self.origins.last() {
+                    Some(path) => E::InFile {
+                        path: path.clone(),
+                        error: Box::new(error),
+                    },
+                    None => error,
+                });
+            }
+        }
+        self.origins.pop();
+
+        Ok(())
+    }
+
     fn emit_acl(
         &mut self,
         acl: &TokenData,
@@ -176,7 +539,7 @@ impl<'a> AstEmitter<'a> {
 
         self.e.acl_keyword()?;
         self.e.ident(name.content)?;
-        self.e.body_start()?;
+        self.e.body_start(Block::Declaration)?;
         for entry in entries {
             self.emit_acl_entry(entry)?;
         }
@@ -219,10 +582,8 @@ impl<'a> AstEmitter<'a> {
 
         self.e.probe_keyword()?;
         self.e.ident(name.content)?;
-        self.e.body_start()?;
-        for prop in properties {
-            self.emit_backend_property(&prop.name, &prop.op, &prop.value)?;
-        }
+        self.e.body_start(Block::Declaration)?;
+        self.emit_backend_properties(properties)?;
 
         self.emit_all_trivia(rbrace)?;
         self.e.body_end()?;
@@ -235,6 +596,7 @@ impl<'a> AstEmitter<'a> {
         name: &TokenData,
         op: &TokenData,
         value: &BackendValue,
+        padding: usize,
     ) -> R {
         self.emit_all_trivia(name)?;
         self.emit_comments(op)?;
@@ -253,6 +615,7 @@ impl<'a> AstEmitter<'a> {
         }
 
         self.e.ident(name.content)?;
+        self.e.hint_padding(padding);
         self.e.infix_operator("=")?;
         match &value {
             BackendValue::Expression { expr, .. } => {
@@ -262,10 +625,8 @@ impl<'a> AstEmitter<'a> {
             BackendValue::Composite {
                 properties, rbrace, ..
             } => {
-                self.e.body_start()?;
-                for prop in properties {
-                    self.emit_backend_property(&prop.name, &prop.op, &prop.value)?;
-                }
+                self.e.body_start(Block::Value)?;
+                self.emit_backend_properties(properties)?;
 
                 self.emit_all_trivia(rbrace)?;
                 self.e.body_end()?;
@@ -282,17 +643,19 @@ impl<'a> AstEmitter<'a> {
         Ok(())
     }
 
+    /// Emits comments inside `expr` before the statement it is part of, except those on lines of
+    /// their own in a broken expression, which stay in place.
     fn emit_expression_comments(&mut self, expr: &Expression) -> R {
         match expr {
-            Expression::Ident(i) => self.emit_comments(i)?,
-            Expression::Literal(l) => self.emit_comments(l)?,
+            Expression::Ident(i) => self.emit_hoisted_comments(i)?,
+            Expression::Literal(l) => self.emit_hoisted_comments(l)?,
             Expression::Neg { op, expr } => {
-                self.emit_comments(op)?;
+                self.emit_hoisted_comments(op)?;
                 self.emit_expression_comments(expr)?;
             }
             Expression::Binary { left, op, right } => {
                 self.emit_expression_comments(left)?;
-                self.emit_comments(op)?;
+                self.emit_hoisted_comments(op)?;
                 self.emit_expression_comments(right)?;
             }
             Expression::IdentCall(c) => self.emit_ident_call_trivia(c, false)?,
@@ -301,14 +664,52 @@ impl<'a> AstEmitter<'a> {
                 expr,
                 rparen,
             } => {
-                self.emit_comments(lparen)?;
+                self.emit_hoisted_comments(lparen)?;
                 self.emit_expression_comments(expr)?;
-                self.emit_comments(rparen)?;
+                self.emit_hoisted_comments(rparen)?;
             }
         }
         Ok(())
     }
 
+    fn emit_hoisted_comments(&mut self, token: &TokenData) -> R {
+        if comments_stay_in_place(token)? {
+            return Ok(());
+        }
+        self.emit_comments(token)
+    }
+
+    /// Emits line breaks before a token of an expression, together with the comments between
+    /// them if they stay in place.
+    fn emit_expression_trivia(&mut self, token: &TokenData) -> R {
+        if std::mem::take(&mut self.expression_trivia_emitted) {
+            return Ok(());
+        }
+        if !comments_stay_in_place(token)? {
+            return self.emit_newlines(token);
+        }
+        let mut newlines = 0;
+        for t in lex_trivia(token.pre_trivia)? {
+            match t {
+                TriviaToken::Newline => newlines += 1,
+                TriviaToken::LineComment(s)
+                | TriviaToken::MultilineComment(s)
+                | TriviaToken::InlineCCode(s) => {
+                    if newlines > 0 {
+                        self.e.hint_allow_line_break();
+                        self.e.newlines(newlines)?;
+                        newlines = 0;
+                    }
+                    self.e.comment(s)?;
+                }
+            }
+        }
+        if newlines > 0 {
+            self.e.newlines(newlines)?;
+        }
+        Ok(())
+    }
+
     fn emit_ident_call_trivia(
         &mut self,
         expr: &IdentCallExpression,
@@ -317,13 +718,13 @@ impl<'a> AstEmitter<'a> {
         if emit_all_from_first {
             self.emit_all_trivia(&expr.name)?;
         } else {
-            self.emit_comments(&expr.name)?;
+            self.emit_hoisted_comments(&expr.name)?;
         }
         self.emit_comments(&expr.lparen)?;
         for (arg, comma) in expr.args.iter() {
             match arg {
                 FunctionCallArg::Named { name, op, value } => {
-                    self.emit_comments(name)?;
+                    self.emit_hoisted_comments(name)?;
                     self.emit_comments(op)?;
                     self.emit_expression_comments(value)?;
                 }
@@ -336,7 +737,7 @@ impl<'a> AstEmitter<'a> {
                 self.emit_comments(tok)?;
             }
         }
-        self.emit_comments(&expr.rparen)?;
+        self.emit_hoisted_comments(&expr.rparen)?;
         Ok(())
     }
 
@@ -346,25 +747,35 @@ impl<'a> AstEmitter<'a> {
         }
         match expr {
             Expression::Ident(i) => {
-                self.emit_newlines(i)?;
+                self.emit_expression_trivia(i)?;
                 self.e.ident(i.content)?;
             }
             Expression::Literal(l) => {
-                self.emit_newlines(l)?;
+                self.emit_expression_trivia(l)?;
                 self.e.ident(l.content)?;
             }
             Expression::Neg { op, expr } => {
-                self.emit_newlines(op)?;
+                self.emit_expression_trivia(op)?;
                 self.e.prefix_operator("!")?;
                 self.emit_expression(expr, false)?;
             }
             Expression::Binary { left, op, right } => {
-                self.emit_expression(left, false)?;
-                self.emit_newlines(op)?;
-                self.e.infix_operator(op.content)?;
-                self.emit_expression(right, false)?;
+                let concatenation = op.content == "+" && is_string_concatenation(expr);
+                if concatenation {
+                    // A line break before the chain decides the column it starts at.
+                    self.emit_newlines(expr.first_token())?;
+                }
+                if concatenation && self.exceeds_max_width(expr) {
+                    self.emit_broken_concatenation(expr)?;
+                } else {
+                    self.emit_expression(left, false)?;
+                    self.emit_expression_trivia(op)?;
+                    self.e.infix_operator(op.content)?;
+                    self.emit_expression(right, false)?;
+                }
             }
             Expression::IdentCall(e) => {
+                self.emit_expression_trivia(&e.name)?;
                 self.emit_ident_call(e)?;
             }
             Expression::Parenthesized {
@@ -372,11 +783,11 @@ impl<'a> AstEmitter<'a> {
                 expr,
                 rparen,
             } => {
-                self.emit_newlines(lparen)?;
+                self.emit_expression_trivia(lparen)?;
                 self.e.l_paren()?;
                 self.emit_expression(expr, false)?;
                 self.e.hint_allow_line_break();
-                self.emit_newlines(rparen)?;
+                self.emit_expression_trivia(rparen)?;
                 self.e.r_paren()?;
             }
         };
@@ -386,6 +797,85 @@ impl<'a> AstEmitter<'a> {
         Ok(())
     }
 
+    /// Whether `expr` does not fit in the line when printed at the current column.
+    fn exceeds_max_width(&self, expr: &Expression) -> bool {
+        self.e.column() + self.flat_width(expr) > self.style.max_width
+    }
+
+    /// Emits a chain of `+` with one operand per line, aligned with the first one. Comments on
+    /// lines of their own before an operator or operand go on aligned lines before the operand.
+    fn emit_broken_concatenation(&mut self, expr: &Expression) -> R {
+        let column = self.e.column();
+        let mut operands = concatenation_operands(expr).into_iter();
+        if let Some((_, first)) = operands.next() {
+            self.emit_expression(first, false)?;
+        }
+        for (op, operand) in operands {
+            if self.style.concatenation_break == BreakPosition::AfterOperator {
+                self.e.infix_operator("+")?;
+            }
+            for token in op.into_iter().chain([operand.first_token()]) {
+                if comments_stay_in_place(token)? {
+                    for t in lex_trivia(token.pre_trivia)? {
+                        match t {
+                            TriviaToken::LineComment(s)
+                            | TriviaToken::MultilineComment(s)
+                            | TriviaToken::InlineCCode(s) => self.e.aligned_comment(column, s)?,
+                            TriviaToken::Newline => {}
+                        }
+                    }
+                }
+            }
+            match self.style.concatenation_break {
+                BreakPosition::BeforeOperator => {
+                    self.e.aligned_line_break(column.saturating_sub(2))?;
+                    self.e.leading_operator("+")?;
+                }
+                BreakPosition::AfterOperator => self.e.aligned_line_break(column)?,
+            }
+            self.expression_trivia_emitted = true;
+            self.emit_expression(operand, false)?;
+        }
+        Ok(())
+    }
+
+    /// Width of `expr` printed on a single line.
+    fn flat_width(&self, expr: &Expression) -> usize {
+        let width = |t: &TokenData| t.content.chars().count();
+        match expr {
+            Expression::Ident(t) | Expression::Literal(t) => width(t),
+            Expression::Neg { expr, .. } => {
+                1 + usize::from(self.style.space_after_not) + self.flat_width(expr)
+            }
+            Expression::Binary { left, op, right } => {
+                self.flat_width(left) + width(op) + 2 + self.flat_width(right)
+            }
+            Expression::Parenthesized { expr, .. } => 2 + self.flat_width(expr),
+            Expression::IdentCall(call) => {
+                let named_operator = if self.style.spaces_around_named_args {
+                    3
+                } else {
+                    1
+                };
+                let args: usize = call
+                    .args
+                    .iter()
+                    .map(|(arg, comma)| {
+                        let separator = if comma.is_some() { 2 } else { 0 };
+                        separator
+                            + match arg {
+                                FunctionCallArg::Named { name, value, .. } => {
+                                    width(name) + named_operator + self.flat_width(value)
+                                }
+                                FunctionCallArg::Positional(e) => self.flat_width(e),
+                            }
+                    })
+                    .sum();
+                width(&call.name) + usize::from(self.style.space_before_call_paren) + 2 + args
+            }
+        }
+    }
+
     fn emit_ident_call(&mut self, e: &IdentCallExpression) -> R {
         self.emit_newlines(&e.name)?;
         self.e.ident(e.name.content)?;
@@ -399,16 +889,16 @@ impl<'a> AstEmitter<'a> {
             };
             match arg {
                 FunctionCallArg::Named { name, value, .. } => {
-                    self.emit_newlines(name)?;
+                    self.emit_expression_trivia(name)?;
                     self.e.ident(name.content)?;
-                    self.e.infix_operator("=")?;
+                    self.e.named_argument_operator()?;
                     self.emit_expression(value, true)?;
                 }
                 FunctionCallArg::Positional(p) => self.emit_expression(p, true)?,
             };
         }
         self.e.hint_allow_line_break();
-        self.emit_newlines(&e.rparen)?;
+        self.emit_expression_trivia(&e.rparen)?;
         self.e.r_paren()?;
         Ok(())
     }
@@ -444,10 +934,8 @@ impl<'a> AstEmitter<'a> {
 
                 self.e.backend_keyword()?;
                 self.e.ident(name.content)?;
-                self.e.body_start()?;
-                for prop in properties {
-                    self.emit_backend_property(&prop.name, &prop.op, &prop.value)?;
-                }
+                self.e.body_start(Block::Declaration)?;
+                self.emit_backend_properties(properties)?;
 
                 self.emit_all_trivia(rbrace)?;
                 self.e.body_end()?;
@@ -471,10 +959,8 @@ impl<'a> AstEmitter<'a> {
 
         self.e.sub_keyword()?;
         self.e.ident(name.content)?;
-        self.e.body_start()?;
-        for st in statements {
-            self.emit_statement(st)?;
-        }
+        self.e.body_start(Block::Sub)?;
+        self.emit_statements(statements, rbrace)?;
 
         self.emit_all_trivia(rbrace)?;
         self.e.body_end()?;
@@ -499,6 +985,7 @@ impl<'a> AstEmitter<'a> {
 
                 self.e.set_keyword()?;
                 self.e.ident(ident.content)?;
+                self.e.hint_padding(std::mem::take(&mut self.set_padding));
                 self.e.infix_operator(op.content)?;
                 self.emit_expression(expr, true)?;
                 self.e.semicolon()?;
@@ -551,21 +1038,18 @@ impl<'a> AstEmitter<'a> {
                 self.e.hint_allow_line_break();
                 self.emit_newlines(rparen)?;
                 self.e.r_paren()?;
-                self.e.body_start()?;
-                for st in body {
-                    self.emit_statement(st)?;
-                }
+                self.e.body_start(Block::If)?;
+                self.emit_statements(body, rbrace)?;
                 self.emit_all_trivia(rbrace)?;
                 for ei in elseifs {
                     self.e.body_end()?;
-                    self.e.else_keyword()?;
-                    self.e.if_keyword()?;
+                    self.e.else_if_keyword()?;
                     self.e.l_paren()?;
                     self.emit_expression(&ei.condition, true)?;
                     self.e.hint_allow_line_break();
                     self.emit_newlines(&ei.rparen)?;
                     self.e.r_paren()?;
-                    self.e.body_start()?;
+                    self.e.body_start(Block::If)?;
 
                     for t in &ei.elseif {
                         self.emit_comments(t)?;
@@ -575,23 +1059,19 @@ impl<'a> AstEmitter<'a> {
                     self.emit_comments(&ei.rparen)?;
                     self.emit_comments(&ei.lbrace)?;
 
-                    for st in &ei.body {
-                        self.emit_statement(st)?;
-                    }
+                    self.emit_statements(&ei.body, &ei.rbrace)?;
 
                     self.emit_all_trivia(&ei.rbrace)?;
                 }
                 if let Some(e) = else_st {
                     self.e.body_end()?;
                     self.e.else_keyword()?;
-                    self.e.body_start()?;
+                    self.e.body_start(Block::If)?;
 
                     self.emit_comments(&e.else_t)?;
                     self.emit_comments(&e.lbrace)?;
 
-                    for st in &e.body {
-                        self.emit_statement(st)?;
-                    }
+                    self.emit_statements(&e.body, &e.rbrace)?;
 
                     self.emit_all_trivia(&e.rbrace)?;
                 }
@@ -662,11 +1142,113 @@ impl<'a> AstEmitter<'a> {
                 self.e.new_keyword()?;
                 self.e.ident(name.content)?;
                 self.e.infix_operator("=")?;
+                self.emit_expression_trivia(&value.name)?;
                 self.emit_ident_call(value)?;
                 self.e.semicolon()?;
             }
-            Statement::Include(i) => self.emit_include(i)?,
+            Statement::Include(i) => self.emit_include(i, false)?,
+            Statement::Placeholder { token, semi } => {
+                self.emit_placeholder(token, semi.as_ref())?
+            }
         };
         Ok(())
     }
 }
+
+/// Whether `token` was lexed from `source`, as opposed to another file or a built syntax tree.
+fn is_token_of(source: &str, token: &TokenData) -> bool {
+    source
+        .get(token.offset..)
+        .is_some_and(|s| std::ptr::eq(s.as_ptr(), token.content.as_ptr()))
+}
+
+/// `vclfmt` directives in the comments before `token`, in order.
+pub(crate) fn directives(token: &TokenData) -> Result<Vec<Directive>, E> {
+    let mut found = vec![];
+    for t in lex_trivia(token.pre_trivia)? {
+        let comment = match t {
+            TriviaToken::LineComment(s) | TriviaToken::MultilineComment(s) => s,
+            _ => continue,
+        };
+        let text = comment
+            .trim_start_matches(['#', '/', '*'])
+            .trim_end_matches(['*', '/'])
+            .trim();
+        let directive = match text.strip_prefix("vclfmt:").map(str::trim) {
+            Some("off") => Directive::Off,
+            Some("on") => Directive::On,
+            Some("skip") => Directive::Skip,
+            _ => continue,
+        };
+        found.push(directive);
+    }
+    Ok(found)
+}
+
+/// Which of `items` `vclfmt` directives keep as written, by the same rules as emitting: `skip`
+/// keeps the next item, `off` every item up to the first one preceded by `on`.
+pub(crate) fn kept_as_written<'t, T>(
+    items: &'t [T],
+    first_token: fn(&'t T) -> &'t TokenData<'t>,
+) -> Result<Vec<bool>, E> {
+    let mut kept = Vec::with_capacity(items.len());
+    let mut off = false;
+    for item in items {
+        let found = directives(first_token(item))?;
+        if found.contains(&Directive::On) {
+            off = false;
+        }
+        match found.last() {
+            Some(Directive::Skip) => kept.push(true),
+            Some(Directive::Off) => {
+                off = true;
+                kept.push(true);
+            }
+            _ => kept.push(off),
+        }
+    }
+    Ok(kept)
+}
+
+/// Whether comments before `token` stay where they are rather than being moved before the
+/// statement: true when they are on lines of their own, i.e. the expression is broken there.
+fn comments_stay_in_place(token: &TokenData) -> Result<bool, E> {
+    let trivia = lex_trivia(token.pre_trivia)?;
+    Ok(trivia.contains(&TriviaToken::Newline)
+        && trivia.iter().any(|t| !matches!(t, TriviaToken::Newline)))
+}
+
+/// Whether a blank line or a comment on its own line precedes `token`.
+fn separated(token: &TokenData) -> Result<bool, E> {
+    let mut newlines = 0;
+    for t in lex_trivia(token.pre_trivia)? {
+        match t {
+            TriviaToken::Newline => newlines += 1,
+            _ if newlines > 0 => return Ok(true),
+            _ => {}
+        }
+    }
+    Ok(newlines > 1)
+}
+
+/// Whether `expr` is a chain of `+` with a string in it.
+fn is_string_concatenation(expr: &Expression) -> bool {
+    concatenation_operands(expr)
+        .iter()
+        .any(|(_, o)| matches!(o, Expression::Literal(l) if l.content.starts_with(['"', '{'])))
+}
+
+/// Operands of a chain of `+` with the operator before each of them, e.g. `a`, `+ b` and `+ c`
+/// for `a + b + c`.
+fn concatenation_operands<'e, 'a>(
+    expr: &'e Expression<'a>,
+) -> Vec<(Option<&'e TokenData<'a>>, &'e Expression<'a>)> {
+    match expr {
+        Expression::Binary { left, op, right } if op.content == "+" => {
+            let mut operands = concatenation_operands(left);
+            operands.push((Some(op), right));
+            operands
+        }
+        _ => vec![(None, expr)],
+    }
+}
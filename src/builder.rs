@@ -0,0 +1,507 @@
+use typed_arena::Arena;
+
+use crate::{
+    ast::*,
+    ast_emitter::AstEmitter,
+    error::E,
+    lexer::TokenData,
+    parser::{binary_precedence, precedence},
+    style::Style,
+};
+
+/// Constructs syntax trees without source text, e.g. to generate VCL.
+///
+/// Text that doesn't come from a `&'static str` (names built at runtime, string values) is kept in
+/// the builder, so nodes borrow from it. String values are quoted, and operands of binary
+/// expressions are parenthesized where needed, so printed trees always parse back the same.
+/// Names (identifiers, sub names, properties) are used as given.
+///
+/// ```
+/// use vcl_formatter::builder::Builder;
+///
+/// let b = Builder::new();
+/// let file = b.file(vec![
+///     b.vcl_version("4.1"),
+///     b.backend("default", vec![b.property("host", b.string("127.0.0.1"))]),
+/// ]);
+/// assert_eq!(
+///     b.print(&file, 4).unwrap(),
+///     "vcl 4.1;\n\nbackend default {\n    .host = \"127.0.0.1\";\n}\n"
+/// );
+/// ```
+#[derive(Default)]
+pub struct Builder {
+    text: Arena<String>,
+}
+
+pub struct IfBuilder<'b> {
+    b: &'b Builder,
+    condition: Expression<'b>,
+    body: Vec<Statement<'b>>,
+    elseifs: Vec<ElseIfStatement<'b>>,
+    else_st: Option<ElseStatement<'b>>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Formats a built file.
+    pub fn print(&self, sf: &SourceFile, indent: usize) -> Result<String, E> {
+        let mut out = vec![];
+        let style = Style {
+            indent,
+            ..Style::default()
+        };
+        AstEmitter::new(&mut out, &style, 0).emit(sf)?;
+        Ok(String::from_utf8(out).map_err(|e| e.utf8_error())?)
+    }
+
+    fn text(&self, text: impl Into<String>) -> &str {
+        self.text.alloc(text.into())
+    }
+
+    fn token<'b>(&'b self, content: &'b str) -> TokenData<'b> {
+        TokenData::synthetic(content, "")
+    }
+
+    fn owned_token(&self, content: impl Into<String>) -> TokenData<'_> {
+        self.token(self.text(content))
+    }
+
+    /// Declarations are separated by blank lines.
+    pub fn file<'b>(&'b self, declarations: Vec<TopLevelDeclaration<'b>>) -> SourceFile<'b> {
+        let declarations = declarations
+            .into_iter()
+            .enumerate()
+            .map(|(idx, mut td)| {
+                if idx > 0 {
+                    td.first_token_mut().pre_trivia = "\n\n";
+                }
+                td
+            })
+            .collect();
+        SourceFile {
+            declarations,
+            eof: self.token(""),
+        }
+    }
+
+    pub fn vcl_version<'b>(&'b self, version: &str) -> TopLevelDeclaration<'b> {
+        TopLevelDeclaration::VclVersion {
+            vcl: self.token("vcl"),
+            number: self.owned_token(version),
+            semi: self.token(";"),
+        }
+    }
+
+    pub fn import<'b>(&'b self, module: &str) -> TopLevelDeclaration<'b> {
+        TopLevelDeclaration::Import {
+            import: self.token("import"),
+            name: self.owned_token(module),
+            from: None,
+            semi: self.token(";"),
+        }
+    }
+
+    pub fn include<'b>(&'b self, path: &str) -> TopLevelDeclaration<'b> {
+        TopLevelDeclaration::Include(self.include_data(path))
+    }
+
+    fn include_data<'b>(&'b self, path: &str) -> IncludeData<'b> {
+        IncludeData {
+            include: self.token("include"),
+            name: self.owned_token(quote(path)),
+            semi: self.token(";"),
+        }
+    }
+
+    pub fn backend<'b>(
+        &'b self,
+        name: &str,
+        properties: Vec<BackendProperty<'b>>,
+    ) -> TopLevelDeclaration<'b> {
+        TopLevelDeclaration::Backend(BackendData::Defined {
+            backend: self.token("backend"),
+            name: self.owned_token(name),
+            lbrace: self.token("{"),
+            properties,
+            rbrace: self.token("}"),
+        })
+    }
+
+    pub fn probe<'b>(
+        &'b self,
+        name: &str,
+        properties: Vec<BackendProperty<'b>>,
+    ) -> TopLevelDeclaration<'b> {
+        TopLevelDeclaration::Probe {
+            probe: self.token("probe"),
+            name: self.owned_token(name),
+            lbrace: self.token("{"),
+            properties,
+            rbrace: self.token("}"),
+        }
+    }
+
+    /// A backend or probe property, `name` without the leading dot.
+    pub fn property<'b>(&'b self, name: &str, value: Expression<'b>) -> BackendProperty<'b> {
+        self.property_value(
+            name,
+            BackendValue::Expression {
+                expr: value,
+                semi: self.token(";"),
+            },
+        )
+    }
+
+    /// A property consisting of several strings, like a probe's `.request`.
+    pub fn property_strings<'b>(&'b self, name: &str, values: &[&str]) -> BackendProperty<'b> {
+        self.property_value(
+            name,
+            BackendValue::StringList {
+                strings: values
+                    .iter()
+                    .flat_map(|v| string_parts(v))
+                    .map(|p| self.owned_token(quote(p)))
+                    .collect(),
+                semi: self.token(";"),
+            },
+        )
+    }
+
+    /// A property with nested properties, like a backend's inline `.probe`.
+    pub fn property_block<'b>(
+        &'b self,
+        name: &str,
+        properties: Vec<BackendProperty<'b>>,
+    ) -> BackendProperty<'b> {
+        self.property_value(
+            name,
+            BackendValue::Composite {
+                lbrace: self.token("{"),
+                properties,
+                rbrace: self.token("}"),
+            },
+        )
+    }
+
+    fn property_value<'b>(&'b self, name: &str, value: BackendValue<'b>) -> BackendProperty<'b> {
+        BackendProperty {
+            name: self.owned_token(format!(".{name}")),
+            op: self.token("="),
+            value,
+        }
+    }
+
+    pub fn acl<'b>(&'b self, name: &str, entries: Vec<AclEntry<'b>>) -> TopLevelDeclaration<'b> {
+        TopLevelDeclaration::Acl {
+            acl: self.token("acl"),
+            name: self.owned_token(name),
+            lbrace: self.token("{"),
+            entries,
+            rbrace: self.token("}"),
+        }
+    }
+
+    pub fn acl_entry<'b>(&'b self, address: &str, mask: Option<u8>) -> AclEntry<'b> {
+        AclEntry {
+            value: self.owned_token(quote(address)),
+            mask: mask.map(|m| MaskData {
+                op: self.token("/"),
+                mask: self.owned_token(m.to_string()),
+            }),
+            semi: self.token(";"),
+        }
+    }
+
+    pub fn sub<'b>(
+        &'b self,
+        name: &str,
+        statements: Vec<Statement<'b>>,
+    ) -> TopLevelDeclaration<'b> {
+        TopLevelDeclaration::Sub {
+            sub: self.token("sub"),
+            name: self.owned_token(name),
+            lbrace: self.token("{"),
+            statements,
+            rbrace: self.token("}"),
+        }
+    }
+
+    pub fn set<'b>(&'b self, target: &str, value: Expression<'b>) -> Statement<'b> {
+        Statement::Set {
+            set: self.token("set"),
+            ident: self.owned_token(target),
+            op: self.token("="),
+            expr: value,
+            semi: self.token(";"),
+        }
+    }
+
+    pub fn unset<'b>(&'b self, target: &str) -> Statement<'b> {
+        Statement::Unset {
+            unset: self.token("unset"),
+            ident: self.owned_token(target),
+            semi: self.token(";"),
+        }
+    }
+
+    /// `call name;`, calling a sub.
+    pub fn call_sub<'b>(&'b self, name: &str) -> Statement<'b> {
+        Statement::Call {
+            call: self.token("call"),
+            ident: self.owned_token(name),
+            semi: self.token(";"),
+        }
+    }
+
+    /// A function call as a statement, e.g. `std.log("x");`.
+    pub fn invoke<'b>(&'b self, name: &str, args: Vec<Expression<'b>>) -> Statement<'b> {
+        Statement::IdentCall {
+            expr: self.ident_call(name, args.into_iter().map(FunctionCallArg::Positional)),
+            semi: self.token(";"),
+        }
+    }
+
+    pub fn return_<'b>(&'b self, action: &str) -> Statement<'b> {
+        self.return_with(action, None)
+    }
+
+    /// `return (action(args));`, e.g. `return (synth(404, "Not found"));`.
+    pub fn return_with<'b>(
+        &'b self,
+        action: &str,
+        args: Option<Vec<Expression<'b>>>,
+    ) -> Statement<'b> {
+        Statement::Return {
+            return_t: self.token("return"),
+            lparen: self.token("("),
+            name: self.owned_token(action),
+            args: args.map(|args| ReturnArgs {
+                lparen: self.token("("),
+                args: self.delimited(args),
+                rparen: self.token(")"),
+            }),
+            rparen: self.token(")"),
+            semi: self.token(";"),
+        }
+    }
+
+    /// `new name = constructor(args);`.
+    pub fn new_object<'b>(
+        &'b self,
+        name: &str,
+        constructor: &str,
+        args: Vec<Expression<'b>>,
+    ) -> Statement<'b> {
+        Statement::New {
+            new: self.token("new"),
+            name: self.owned_token(name),
+            op: self.token("="),
+            value: self.ident_call(
+                constructor,
+                args.into_iter().map(FunctionCallArg::Positional),
+            ),
+            semi: self.token(";"),
+        }
+    }
+
+    pub fn include_statement<'b>(&'b self, path: &str) -> Statement<'b> {
+        Statement::Include(self.include_data(path))
+    }
+
+    /// Starts an `if` statement; add branches with `else_if` and `else_`, then `build` it.
+    pub fn if_<'b>(&'b self, condition: Expression<'b>, body: Vec<Statement<'b>>) -> IfBuilder<'b> {
+        IfBuilder {
+            b: self,
+            condition,
+            body,
+            elseifs: vec![],
+            else_st: None,
+        }
+    }
+
+    pub fn ident<'b>(&'b self, name: &str) -> Expression<'b> {
+        Expression::Ident(self.owned_token(name))
+    }
+
+    /// A string literal with the given value. Values that can't be written in a single literal
+    /// are split into several concatenated ones.
+    pub fn string<'b>(&'b self, value: &str) -> Expression<'b> {
+        let mut parts = string_parts(value)
+            .into_iter()
+            .map(|p| Expression::Literal(self.owned_token(quote(p))));
+        let first = parts
+            .next()
+            .unwrap_or_else(|| Expression::Literal(self.token("\"\"")));
+        parts.fold(first, |acc, part| self.binary(acc, "+", part))
+    }
+
+    /// A literal written as given, e.g. `10s`, `1KB` or `true`.
+    pub fn literal<'b>(&'b self, text: &str) -> Expression<'b> {
+        Expression::Literal(self.owned_token(text))
+    }
+
+    pub fn number<'b>(&'b self, value: i64) -> Expression<'b> {
+        self.literal(&value.to_string())
+    }
+
+    pub fn bool<'b>(&'b self, value: bool) -> Expression<'b> {
+        Expression::Literal(self.token(if value { "true" } else { "false" }))
+    }
+
+    pub fn negate<'b>(&'b self, expr: Expression<'b>) -> Expression<'b> {
+        Expression::Neg {
+            op: self.token("!"),
+            expr: Box::new(self.operand(expr, u8::MAX)),
+        }
+    }
+
+    /// `left op right`, e.g. `binary(req.url, "~", "^/api")`; `op` is any binary operator.
+    pub fn binary<'b>(
+        &'b self,
+        left: Expression<'b>,
+        op: &str,
+        right: Expression<'b>,
+    ) -> Expression<'b> {
+        let precedence = binary_precedence(op).unwrap_or(0);
+        Expression::Binary {
+            left: Box::new(self.operand(left, precedence)),
+            op: self.owned_token(op),
+            right: Box::new(self.operand(right, precedence + 1)),
+        }
+    }
+
+    pub fn and<'b>(&'b self, left: Expression<'b>, right: Expression<'b>) -> Expression<'b> {
+        self.binary(left, "&&", right)
+    }
+
+    pub fn or<'b>(&'b self, left: Expression<'b>, right: Expression<'b>) -> Expression<'b> {
+        self.binary(left, "||", right)
+    }
+
+    pub fn call<'b>(&'b self, name: &str, args: Vec<Expression<'b>>) -> Expression<'b> {
+        Expression::IdentCall(
+            self.ident_call(name, args.into_iter().map(FunctionCallArg::Positional)),
+        )
+    }
+
+    /// A function call with named arguments following the positional ones.
+    pub fn call_named<'b>(
+        &'b self,
+        name: &str,
+        args: Vec<Expression<'b>>,
+        named: Vec<(&str, Expression<'b>)>,
+    ) -> Expression<'b> {
+        let named = named.into_iter().map(|(n, value)| FunctionCallArg::Named {
+            name: self.owned_token(n),
+            op: self.token("="),
+            value,
+        });
+        let args = args
+            .into_iter()
+            .map(FunctionCallArg::Positional)
+            .chain(named);
+        Expression::IdentCall(self.ident_call(name, args))
+    }
+
+    fn ident_call<'b>(
+        &'b self,
+        name: &str,
+        args: impl Iterator<Item = FunctionCallArg<'b>>,
+    ) -> IdentCallExpression<'b> {
+        IdentCallExpression {
+            name: self.owned_token(name),
+            lparen: self.token("("),
+            args: Box::new(self.delimited(args.collect())),
+            rparen: self.token(")"),
+        }
+    }
+
+    fn delimited<'b, Item>(&'b self, mut items: Vec<Item>) -> DelimitedList<Item, TokenData<'b>> {
+        match items.pop() {
+            Some(last_item) => DelimitedList::WithItems {
+                pairs: items.into_iter().map(|i| (i, self.token(","))).collect(),
+                last_item,
+            },
+            None => DelimitedList::Empty,
+        }
+    }
+
+    /// Parenthesizes `expr` unless it binds at least as tightly as `min_precedence`.
+    fn operand<'b>(&'b self, expr: Expression<'b>, min_precedence: u8) -> Expression<'b> {
+        if precedence(&expr) >= min_precedence {
+            return expr;
+        }
+        Expression::Parenthesized {
+            lparen: self.token("("),
+            expr: Box::new(expr),
+            rparen: self.token(")"),
+        }
+    }
+}
+
+impl<'b> IfBuilder<'b> {
+    pub fn else_if(mut self, condition: Expression<'b>, body: Vec<Statement<'b>>) -> Self {
+        self.elseifs.push(ElseIfStatement {
+            elseif: vec![self.b.token("else"), self.b.token("if")],
+            lparen: self.b.token("("),
+            condition,
+            rparen: self.b.token(")"),
+            lbrace: self.b.token("{"),
+            body,
+            rbrace: self.b.token("}"),
+        });
+        self
+    }
+
+    pub fn else_(mut self, body: Vec<Statement<'b>>) -> Self {
+        self.else_st = Some(ElseStatement {
+            else_t: self.b.token("else"),
+            lbrace: self.b.token("{"),
+            body,
+            rbrace: self.b.token("}"),
+        });
+        self
+    }
+
+    pub fn build(self) -> Statement<'b> {
+        Statement::If {
+            if_t: self.b.token("if"),
+            lparen: self.b.token("("),
+            condition: self.condition,
+            rparen: self.b.token(")"),
+            lbrace: self.b.token("{"),
+            body: self.body,
+            rbrace: self.b.token("}"),
+            elseifs: self.elseifs,
+            else_st: self.else_st,
+        }
+    }
+}
+
+/// Splits a string value so that every part can be written as a single literal: the only
+/// sequence no literal can hold is `"}` when it also contains a quote or a newline.
+fn string_parts(value: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut rest = value;
+    while let Some(idx) = rest.find("\"}") {
+        parts.push(&rest[..=idx]);
+        rest = &rest[idx + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(rest);
+    }
+    parts
+}
+
+/// Writes a string value as a literal, using the long form when the short one can't hold it.
+fn quote(value: &str) -> String {
+    if value.contains(['"', '\n', '\r']) {
+        format!("{{\"{value}\"}}")
+    } else {
+        format!("\"{value}\"")
+    }
+}
@@ -0,0 +1,98 @@
+use std::{borrow::Cow, io::Write};
+
+use crate::{
+    ast::{Fragment, IncludeData},
+    ast_emitter::{AstEmitter, IncludeExpander},
+    error::{E, R},
+    lexer::{self, Token},
+    parser::{self, FragmentKind},
+    resolver::Project,
+    style::Style,
+};
+
+struct ProjectExpander<'p, 't> {
+    origins: Vec<String>,
+    fragments: &'p [Fragment<'t>],
+    targets: Vec<(&'p IncludeData<'t>, usize)>,
+}
+
+impl<'p, 't> IncludeExpander for ProjectExpander<'p, 't> {
+    fn expand<'s>(&'s self, include: &IncludeData) -> Option<(&'s Fragment<'s>, &'s str)> {
+        self.targets
+            .iter()
+            .find(|(i, _)| std::ptr::eq(*i, include))
+            .map(|(_, target)| (&self.fragments[*target], self.origins[*target].as_str()))
+    }
+}
+
+/// Emits the project's entrypoint with all includes replaced by contents of the included files,
+/// each rewritten as `style` asks for first.
+pub fn bundle(project: &Project, style: &Style, markers: bool, out: &mut dyn Write) -> R {
+    let origins: Vec<String> = project
+        .units
+        .iter()
+        .map(|u| u.path.display().to_string())
+        .collect();
+    let in_file = |idx: usize| {
+        let path = origins[idx].clone();
+        move |e| E::InFile {
+            path,
+            error: Box::new(e),
+        }
+    };
+
+    // Rewriting options apply to every file before it is inlined.
+    let sources = project
+        .units
+        .iter()
+        .enumerate()
+        .map(|(idx, u)| {
+            let fragment = if idx == 0 {
+                None
+            } else {
+                Some(FragmentKind::Auto)
+            };
+            crate::rewrite(&u.source, style, fragment, &[]).map_err(in_file(idx))
+        })
+        .collect::<Result<Vec<Cow<str>>, E>>()?;
+    let tokens = sources
+        .iter()
+        .enumerate()
+        .map(|(idx, s)| lexer::lex(s).map_err(in_file(idx)))
+        .collect::<Result<Vec<Vec<Token>>, E>>()?;
+    let fragments = tokens
+        .iter()
+        .enumerate()
+        .map(|(idx, t)| {
+            let kind = if idx == 0 {
+                FragmentKind::TopLevel
+            } else {
+                FragmentKind::Auto
+            };
+            parser::parse_fragment(t, kind).map_err(in_file(idx))
+        })
+        .collect::<Result<Vec<Fragment>, E>>()?;
+
+    let mut targets = vec![];
+    for (unit, fragment) in project.units.iter().zip(&fragments) {
+        // Matched by name, as organizing may have moved includes around.
+        for include in fragment.includes() {
+            let resolved = unit.includes.iter().find(|r| r.name == include.path());
+            if let Some(target) = resolved.and_then(|r| project.position(&r.path)) {
+                targets.push((include, target));
+            }
+        }
+    }
+
+    let expander = ProjectExpander {
+        origins,
+        fragments: &fragments,
+        targets,
+    };
+    let mut emitter = sources.iter().fold(
+        AstEmitter::new(out, style, 0).with_includes(&expander, &expander.origins[0], markers),
+        |emitter, source| emitter.with_source(source),
+    );
+    emitter.emit_fragment(&fragments[0])?;
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::{
+    ast::{Fragment, SourceFile},
+    error::{E, R},
+    lexer::{self, Delimiters},
+    parser::{self, FragmentKind},
+};
+
+/// Version of the schema written by `ast_json`, documented in `docs/ast-json.md`.
+pub const AST_JSON_VERSION: u32 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum EmitFormat {
+    /// The syntax tree as JSON, including positions and comments
+    AstJson,
+    /// The token stream, one token per line
+    Tokens,
+}
+
+#[derive(Serialize)]
+struct AstJson<'s, 'a> {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_file: Option<&'s SourceFile<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fragment: Option<&'s Fragment<'a>>,
+}
+
+pub fn emit(
+    format: EmitFormat,
+    source: &str,
+    fragment: Option<FragmentKind>,
+    placeholders: &[Delimiters],
+    out: &mut dyn Write,
+) -> R {
+    match format {
+        EmitFormat::AstJson => ast_json(source, fragment, placeholders, out),
+        EmitFormat::Tokens => tokens(source, placeholders, out),
+    }
+}
+
+/// Writes the syntax tree of a complete file, or of a fragment if its kind is given.
+pub fn ast_json(
+    source: &str,
+    fragment: Option<FragmentKind>,
+    placeholders: &[Delimiters],
+    out: &mut dyn Write,
+) -> R {
+    let tokens = lexer::lex_with_placeholders(source, placeholders)?;
+    let (source_file, fragment) = match fragment {
+        Some(kind) => (None, Some(parser::parse_fragment(&tokens, kind)?)),
+        None => (Some(parser::parse(&tokens)?), None),
+    };
+    let document = AstJson {
+        version: AST_JSON_VERSION,
+        source_file: source_file.as_ref(),
+        fragment: fragment.as_ref(),
+    };
+    serde_json::to_writer_pretty(&mut *out, &document)
+        .map_err(|e| E::FailedToWriteOutput(e.into()))?;
+    writeln!(out).map_err(E::FailedToWriteOutput)?;
+    Ok(())
+}
+
+/// Writes every token with its position, e.g. `3:5 Ident("req.url")`.
+pub fn tokens(source: &str, placeholders: &[Delimiters], out: &mut dyn Write) -> R {
+    for token in lexer::lex_with_placeholders(source, placeholders)? {
+        if let Some(d) = token.data() {
+            writeln!(out, "{}:{} {:?}", d.line, d.column, token).map_err(E::FailedToWriteOutput)?;
+        }
+    }
+    Ok(())
+}
@@ -1,6 +1,21 @@
 use std::io::Write;
 
-use crate::error::R;
+use crate::{
+    error::R,
+    style::{BraceStyle, Style},
+};
+
+/// Kinds of blocks that can be formatted differently.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Block {
+    Sub,
+    /// Backends, probes and ACLs
+    Declaration,
+    /// Bodies of `if`, `else if` and `else`
+    If,
+    /// Composite values of backend properties, e.g. `.probe = { ... }`
+    Value,
+}
 
 pub trait Emitter {
     fn vcl_keyword(&mut self) -> R;
@@ -10,13 +25,16 @@ pub trait Emitter {
     fn string(&mut self, string: &str) -> R;
     fn import_keyword(&mut self) -> R;
     fn ident(&mut self, ident: &str) -> R;
+    fn placeholder(&mut self, placeholder: &str) -> R;
+    fn verbatim(&mut self, code: &str) -> R;
     #[allow(clippy::wrong_self_convention)]
     fn from_keyword(&mut self) -> R;
     fn probe_keyword(&mut self) -> R;
-    fn body_start(&mut self) -> R;
+    fn body_start(&mut self, block: Block) -> R;
     fn body_end(&mut self) -> R;
     fn prefix_operator(&mut self, op: &str) -> R;
     fn infix_operator(&mut self, op: &str) -> R;
+    fn named_argument_operator(&mut self) -> R;
     fn backend_keyword(&mut self) -> R;
     fn none_keyword(&mut self) -> R;
     fn acl_keyword(&mut self) -> R;
@@ -30,6 +48,7 @@ pub trait Emitter {
     fn unset_keyword(&mut self) -> R;
     fn if_keyword(&mut self) -> R;
     fn else_keyword(&mut self) -> R;
+    fn else_if_keyword(&mut self) -> R;
     fn return_keyword(&mut self) -> R;
     fn comment(&mut self, comment: &str) -> R;
     fn newlines(&mut self, how_many: usize) -> R;
@@ -38,11 +57,48 @@ pub trait Emitter {
     fn hint_expression_tree_start(&mut self);
     fn hint_expression_tree_end(&mut self);
     fn hint_allow_line_break(&mut self);
+    fn hint_line_end(&mut self);
+    fn hint_blank_lines(&mut self, how_many: usize);
+    fn hint_padding(&mut self, spaces: usize);
+    /// Column the next token starts at.
+    fn column(&self) -> usize;
+    /// Puts the next token on a new line, starting at `column` rather than at the indentation.
+    fn aligned_line_break(&mut self, column: usize) -> R;
+    /// A binary operator at the start of a continuation line.
+    fn leading_operator(&mut self, op: &str) -> R;
+    /// Puts `comment` on a new line, starting at `column` rather than at the indentation.
+    fn aligned_comment(&mut self, column: usize, comment: &str) -> R;
+}
+
+/// Writer that keeps track of the column the next character goes to.
+struct ColumnWriter<'a> {
+    inner: &'a mut dyn Write,
+    column: usize,
+}
+
+impl Write for ColumnWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let line = match buf[..written].iter().rposition(|b| *b == b'\n') {
+            Some(idx) => {
+                self.column = 0;
+                &buf[idx + 1..written]
+            }
+            None => &buf[..written],
+        };
+        // Characters rather than bytes: UTF-8 continuation bytes are not counted.
+        self.column += line.iter().filter(|b| **b & 0xC0 != 0x80).count();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 pub struct StandardEmitter<'a> {
-    write: &'a mut dyn Write,
-    indent_step: usize,
+    write: ColumnWriter<'a>,
+    style: Style,
     needs_whitespace: bool,
     new_line: bool,
     in_string_list: bool,
@@ -50,6 +106,10 @@ pub struct StandardEmitter<'a> {
     new_line_pending: bool,
     allow_line_break: bool,
     ident_before_lparen: bool,
+    in_line_comment: bool,
+    block_start: bool,
+    blank_lines: usize,
+    padding: usize,
     nest_level: usize,
     materialized_nest_levels: Vec<usize>,
 }
@@ -61,10 +121,13 @@ macro_rules! w {
 }
 
 impl<'a> StandardEmitter<'a> {
-    pub fn new(write: &'a mut dyn Write, indent_step: usize) -> Self {
+    pub fn new(write: &'a mut dyn Write, style: Style, base_indent: usize) -> Self {
         Self {
-            write,
-            indent_step,
+            write: ColumnWriter {
+                inner: write,
+                column: 0,
+            },
+            style,
             needs_whitespace: false,
             new_line: true,
             in_string_list: false,
@@ -72,8 +135,12 @@ impl<'a> StandardEmitter<'a> {
             new_line_pending: false,
             allow_line_break: false,
             ident_before_lparen: false,
-            nest_level: 0,
-            materialized_nest_levels: vec![],
+            in_line_comment: false,
+            block_start: false,
+            blank_lines: 0,
+            padding: 0,
+            nest_level: base_indent,
+            materialized_nest_levels: (1..=base_indent).collect(),
         }
     }
 
@@ -83,6 +150,11 @@ impl<'a> StandardEmitter<'a> {
         }
 
         if self.new_line {
+            if self.block_start && self.style.trim_blank_lines_in_blocks {
+                self.blank_lines = 0;
+            }
+            w!(self.write, "{}", "\n".repeat(self.blank_lines))?;
+
             if self.nest_level > self.last_nest() {
                 self.materialized_nest_levels.push(self.nest_level);
             }
@@ -91,7 +163,7 @@ impl<'a> StandardEmitter<'a> {
                 self.write,
                 "{:<i$}",
                 "",
-                i = self.indent_step * self.materialized_nest_levels.len()
+                i = self.style.indent * self.materialized_nest_levels.len()
             )?;
         } else if self.needs_whitespace {
             w!(self.write, " ")?;
@@ -100,6 +172,8 @@ impl<'a> StandardEmitter<'a> {
         self.needs_whitespace = false;
         self.new_line_pending = false;
         self.allow_line_break = false;
+        self.block_start = false;
+        self.blank_lines = 0;
 
         Ok(())
     }
@@ -107,6 +181,7 @@ impl<'a> StandardEmitter<'a> {
     fn line(&mut self) -> R {
         w!(self.write, "\n")?;
         self.new_line = true;
+        self.in_line_comment = false;
         self.new_line_pending = false;
 
         Ok(())
@@ -197,6 +272,23 @@ impl<'a> Emitter for StandardEmitter<'a> {
         Ok(())
     }
 
+    fn placeholder(&mut self, placeholder: &str) -> R {
+        self.flush_preceding_whitespace()?;
+        w!(self.write, "{}", placeholder)?;
+        self.needs_whitespace = true;
+        self.ident_before_lparen = true;
+
+        Ok(())
+    }
+
+    fn verbatim(&mut self, code: &str) -> R {
+        self.flush_preceding_whitespace()?;
+        w!(self.write, "{}", code)?;
+        self.new_line_pending = true;
+
+        Ok(())
+    }
+
     fn from_keyword(&mut self) -> R {
         self.keyword("from")?;
         Ok(())
@@ -207,16 +299,35 @@ impl<'a> Emitter for StandardEmitter<'a> {
         Ok(())
     }
 
-    fn body_start(&mut self) -> R {
-        self.needs_whitespace = false;
-        w!(self.write, " {{")?;
+    fn body_start(&mut self, block: Block) -> R {
+        let braces = match block {
+            Block::Sub => self.style.sub_braces,
+            Block::Declaration => self.style.declaration_braces,
+            Block::If => self.style.if_braces,
+            Block::Value => BraceStyle::SameLine,
+        };
+        match braces {
+            BraceStyle::SameLine => {
+                self.needs_whitespace = false;
+                w!(self.write, " {{")?;
+            }
+            BraceStyle::NextLine => {
+                self.new_line_pending = true;
+                self.flush_preceding_whitespace()?;
+                w!(self.write, "{{")?;
+            }
+        }
         self.new_line_pending = true;
+        self.block_start = true;
         self.increase_nest();
 
         Ok(())
     }
 
     fn body_end(&mut self) -> R {
+        if self.style.trim_blank_lines_in_blocks {
+            self.blank_lines = 0;
+        }
         self.decrease_nest();
         self.flush_preceding_whitespace()?;
         w!(self.write, "}}")?;
@@ -229,16 +340,25 @@ impl<'a> Emitter for StandardEmitter<'a> {
     fn prefix_operator(&mut self, op: &str) -> R {
         self.flush_preceding_whitespace()?;
         w!(self.write, "{}", op)?;
+        self.needs_whitespace = op == "!" && self.style.space_after_not;
 
         Ok(())
     }
 
     fn infix_operator(&mut self, op: &str) -> R {
         self.needs_whitespace = false;
-        if op == "/" && self.in_acl {
+        if op == "/" && self.in_acl && !self.style.spaces_around_acl_mask {
+            w!(self.write, "{op}")?;
+        } else if self.new_line || self.new_line_pending {
+            // After a comment kept inside a broken expression.
+            self.flush_preceding_whitespace()?;
             w!(self.write, "{op}")?;
+            self.needs_whitespace = true;
+            self.allow_line_break = true;
+            self.ident_before_lparen = false;
         } else {
-            w!(self.write, " {op}")?;
+            let padding = " ".repeat(std::mem::take(&mut self.padding));
+            w!(self.write, "{padding} {op}")?;
             self.needs_whitespace = true;
             self.allow_line_break = true;
             self.ident_before_lparen = false;
@@ -247,6 +367,17 @@ impl<'a> Emitter for StandardEmitter<'a> {
         Ok(())
     }
 
+    fn named_argument_operator(&mut self) -> R {
+        if self.style.spaces_around_named_args {
+            return self.infix_operator("=");
+        }
+        self.needs_whitespace = false;
+        w!(self.write, "=")?;
+        self.ident_before_lparen = false;
+
+        Ok(())
+    }
+
     fn backend_keyword(&mut self) -> R {
         self.keyword("backend")?;
         Ok(())
@@ -275,7 +406,7 @@ impl<'a> Emitter for StandardEmitter<'a> {
 
     fn l_paren(&mut self) -> R {
         if self.ident_before_lparen {
-            self.needs_whitespace = false;
+            self.needs_whitespace = self.style.space_before_call_paren;
             self.ident_before_lparen = false;
         }
         self.flush_preceding_whitespace()?;
@@ -299,6 +430,7 @@ impl<'a> Emitter for StandardEmitter<'a> {
         w!(self.write, ",")?;
         self.needs_whitespace = true;
         self.allow_line_break = true;
+        self.ident_before_lparen = false;
 
         Ok(())
     }
@@ -310,32 +442,49 @@ impl<'a> Emitter for StandardEmitter<'a> {
 
     fn if_keyword(&mut self) -> R {
         self.keyword("if")?;
+        self.needs_whitespace = self.style.space_after_if;
         self.ident_before_lparen = false;
 
         Ok(())
     }
 
     fn else_keyword(&mut self) -> R {
-        self.new_line_pending = false;
+        if self.style.cuddle_else {
+            self.new_line_pending = false;
+        }
         self.needs_whitespace = true;
         self.keyword("else")?;
 
         Ok(())
     }
 
+    fn else_if_keyword(&mut self) -> R {
+        self.else_keyword()?;
+        self.keyword("if")?;
+        self.needs_whitespace = self.style.space_after_elseif;
+        self.ident_before_lparen = false;
+
+        Ok(())
+    }
+
     fn return_keyword(&mut self) -> R {
         self.keyword("return")?;
+        self.needs_whitespace = self.style.space_after_return;
         self.ident_before_lparen = false;
 
         Ok(())
     }
 
     fn comment(&mut self, comment: &str) -> R {
-        self.new_line_pending = false;
+        // Anything written after a line comment on the same line would become part of it.
+        if !self.in_line_comment {
+            self.new_line_pending = false;
+        }
         self.needs_whitespace = true;
         self.flush_preceding_whitespace()?;
         w!(self.write, "{}", comment)?;
         self.new_line_pending = true;
+        self.in_line_comment = comment.starts_with("//") || comment.starts_with('#');
 
         Ok(())
     }
@@ -344,9 +493,10 @@ impl<'a> Emitter for StandardEmitter<'a> {
         assert!(how_many > 0);
         if self.new_line_pending {
             self.line()?;
-            if how_many > 1 {
-                self.line()?;
-            }
+            // Blank lines are only written before the next token, so that they can be dropped
+            // at the start and end of blocks.
+            let blank_lines = (how_many - 1).min(self.style.max_blank_lines);
+            self.blank_lines = self.blank_lines.max(blank_lines);
         } else if self.allow_line_break {
             self.line()?;
         }
@@ -358,7 +508,7 @@ impl<'a> Emitter for StandardEmitter<'a> {
     }
 
     fn file_end(&mut self) -> R {
-        if self.new_line_pending {
+        if self.new_line_pending || !self.new_line {
             self.line()?;
         }
 
@@ -391,4 +541,53 @@ impl<'a> Emitter for StandardEmitter<'a> {
     fn hint_allow_line_break(&mut self) {
         self.allow_line_break = true;
     }
+
+    fn hint_line_end(&mut self) {
+        self.new_line_pending = true;
+    }
+
+    fn hint_blank_lines(&mut self, how_many: usize) {
+        self.blank_lines = self.blank_lines.max(how_many);
+    }
+
+    fn hint_padding(&mut self, spaces: usize) {
+        self.padding = spaces;
+    }
+
+    fn column(&self) -> usize {
+        if self.new_line || self.new_line_pending {
+            let new_level = usize::from(self.nest_level > self.last_nest());
+            self.style.indent * (self.materialized_nest_levels.len() + new_level)
+        } else {
+            self.write.column + usize::from(self.needs_whitespace)
+        }
+    }
+
+    fn aligned_line_break(&mut self, column: usize) -> R {
+        self.line()?;
+        w!(self.write, "{}", " ".repeat(column))?;
+        self.new_line = false;
+        self.needs_whitespace = false;
+        self.allow_line_break = false;
+
+        Ok(())
+    }
+
+    fn leading_operator(&mut self, op: &str) -> R {
+        self.flush_preceding_whitespace()?;
+        w!(self.write, "{op}")?;
+        self.needs_whitespace = true;
+        self.ident_before_lparen = false;
+
+        Ok(())
+    }
+
+    fn aligned_comment(&mut self, column: usize, comment: &str) -> R {
+        self.aligned_line_break(column)?;
+        w!(self.write, "{comment}")?;
+        self.new_line_pending = true;
+        self.in_line_comment = comment.starts_with("//") || comment.starts_with('#');
+
+        Ok(())
+    }
 }
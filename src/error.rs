@@ -9,12 +9,77 @@ pub enum E {
         column: usize,
     },
     LexingTriviaFailed,
+    UnterminatedPlaceholder {
+        line: usize,
+        column: usize,
+    },
     ParsingFailed {
         token: String,
         line: usize,
         column: usize,
     },
     ParsingTriviaFailed,
+    InFile {
+        path: String,
+        error: Box<E>,
+    },
+    InBlock {
+        line: usize,
+        error: Box<E>,
+    },
+    UnterminatedBlock {
+        line: usize,
+    },
+    IncludeNotFound {
+        name: String,
+        line: usize,
+        column: usize,
+    },
+    IncludeCycle(Vec<String>),
+    IncompatibleInclude {
+        name: String,
+        line: usize,
+        column: usize,
+    },
+    CheckFailed(Vec<String>),
+    OutputExists(String),
+    InvalidOutput(Box<E>),
+    TokenMismatch {
+        expected: String,
+        expected_line: usize,
+        expected_column: usize,
+        found: String,
+        line: usize,
+        column: usize,
+    },
+    MissingToken {
+        expected: String,
+        expected_line: usize,
+        expected_column: usize,
+        line: usize,
+        column: usize,
+    },
+    ExtraToken {
+        found: String,
+        line: usize,
+        column: usize,
+    },
+    SyntaxTreeMismatch,
+    InvalidAclAddress {
+        address: String,
+        line: usize,
+        column: usize,
+    },
+    AclMaskOutOfRange {
+        mask: String,
+        max: u8,
+        line: usize,
+        column: usize,
+    },
+    NotIdempotent {
+        line: usize,
+        column: usize,
+    },
 }
 
 impl From<std::str::Utf8Error> for E {
@@ -48,6 +113,12 @@ impl Debug for E {
                 f.write_str("Failed to lex trivia - this is likely a bug")?;
                 Ok(())
             }
+            E::UnterminatedPlaceholder { line, column } => {
+                f.write_fmt(format_args!(
+                    "Unterminated placeholder (line={line}, column={column})"
+                ))?;
+                Ok(())
+            }
             E::ParsingFailed {
                 token,
                 line,
@@ -62,6 +133,117 @@ impl Debug for E {
                 f.write_str("Failed to parse trivia - this is likely a bug")?;
                 Ok(())
             }
+            E::InFile { path, error } => {
+                f.write_fmt(format_args!("{path}: {error:?}"))?;
+                Ok(())
+            }
+            E::InBlock { line, error } => {
+                f.write_fmt(format_args!("VCL block at line {line}: {error:?}"))?;
+                Ok(())
+            }
+            E::UnterminatedBlock { line } => {
+                f.write_fmt(format_args!("Unterminated block (line={line})"))?;
+                Ok(())
+            }
+            E::IncludeNotFound { name, line, column } => {
+                f.write_fmt(format_args!(
+                    "Included file \"{name}\" not found (line={line}, column={column})"
+                ))?;
+                Ok(())
+            }
+            E::IncludeCycle(chain) => {
+                f.write_fmt(format_args!("Include cycle: {}", chain.join(" -> ")))?;
+                Ok(())
+            }
+            E::IncompatibleInclude { name, line, column } => {
+                f.write_fmt(format_args!(
+                    "Included file \"{name}\" can't be inlined here (line={line}, column={column})"
+                ))?;
+                Ok(())
+            }
+            E::OutputExists(path) => {
+                f.write_fmt(format_args!("Refusing to overwrite existing file: {path}"))?;
+                Ok(())
+            }
+            E::InvalidOutput(error) => {
+                f.write_fmt(format_args!(
+                    "Formatted output is not valid VCL - this is likely a bug: {error:?}"
+                ))?;
+                Ok(())
+            }
+            E::TokenMismatch {
+                expected,
+                expected_line,
+                expected_column,
+                found,
+                line,
+                column,
+            } => {
+                f.write_fmt(format_args!(
+                    "Formatting changed the code - this is likely a bug: expected \"{expected}\" (line={expected_line}, column={expected_column}), found \"{found}\" (output line={line}, column={column})"
+                ))?;
+                Ok(())
+            }
+            E::MissingToken {
+                expected,
+                expected_line,
+                expected_column,
+                line,
+                column,
+            } => {
+                f.write_fmt(format_args!(
+                    "Formatting changed the code - this is likely a bug: expected \"{expected}\" (line={expected_line}, column={expected_column}), found the end of the output (output line={line}, column={column})"
+                ))?;
+                Ok(())
+            }
+            E::ExtraToken {
+                found,
+                line,
+                column,
+            } => {
+                f.write_fmt(format_args!(
+                    "Formatting changed the code - this is likely a bug: expected the end of the input, found \"{found}\" (output line={line}, column={column})"
+                ))?;
+                Ok(())
+            }
+            E::SyntaxTreeMismatch => {
+                f.write_str("Formatting changed the syntax tree - this is likely a bug")?;
+                Ok(())
+            }
+            E::InvalidAclAddress {
+                address,
+                line,
+                column,
+            } => {
+                f.write_fmt(format_args!(
+                    "Invalid address \"{address}\" in ACL (line={line}, column={column})"
+                ))?;
+                Ok(())
+            }
+            E::AclMaskOutOfRange {
+                mask,
+                max,
+                line,
+                column,
+            } => {
+                f.write_fmt(format_args!(
+                    "ACL mask {mask} is out of range 0-{max} (line={line}, column={column})"
+                ))?;
+                Ok(())
+            }
+            E::NotIdempotent { line, column } => {
+                f.write_fmt(format_args!(
+                    "Formatting the output again changes it - this is likely a bug (line={line}, column={column})"
+                ))?;
+                Ok(())
+            }
+            E::CheckFailed(files) => {
+                f.write_str("Some files are not formatted:")?;
+                for file in files {
+                    f.write_fmt(format_args!("\n  {file}"))?;
+                }
+                Ok(())
+            }
         }
     }
 }
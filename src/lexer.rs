@@ -1,12 +1,185 @@
 use logos::{Lexer, Logos, Skip};
 
+/// Byte order mark that some editors put at the start of UTF-8 files.
+pub const BOM: &str = "\u{feff}";
+
 pub fn lex(data_str: &str) -> Result<Vec<Token<'_>>, crate::error::E> {
-    let lex = Token::lexer(data_str);
-    let iter = TokenIter {
+    let (start, state) = initial_state(data_str);
+    Ok(lex_range(data_str, start, data_str.len(), state)?.0)
+}
+
+/// Where lexing `data_str` starts: after its byte order mark, if it has one.
+fn initial_state(data_str: &str) -> (usize, LexerState) {
+    let start = if data_str.starts_with(BOM) {
+        BOM.len()
+    } else {
+        0
+    };
+    let state = LexerState {
+        line: 0,
+        last_line_end: start,
+        last_token_end: start,
+    };
+    (start, state)
+}
+
+/// Opening and closing delimiters of template placeholders, e.g. `{{` and `}}`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delimiters {
+    pub open: String,
+    pub close: String,
+}
+
+impl Delimiters {
+    /// Delimiters of Jinja/Go templates (`{{ }}`, `{% %}`, `{# #}`) and envsubst (`${ }`).
+    pub fn template_defaults() -> Vec<Delimiters> {
+        [("{{", "}}"), ("{%", "%}"), ("{#", "#}"), ("${", "}")]
+            .into_iter()
+            .map(|(open, close)| Delimiters {
+                open: open.to_string(),
+                close: close.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Like `lex`, but regions enclosed in any of the `placeholders` delimiters become single
+/// `Placeholder` tokens. Delimiters inside strings and comments are not recognized.
+pub fn lex_with_placeholders<'a>(
+    data_str: &'a str,
+    placeholders: &[Delimiters],
+) -> Result<Vec<Token<'a>>, crate::error::E> {
+    if placeholders.is_empty() {
+        return lex(data_str);
+    }
+
+    let mut tokens = vec![];
+    let (mut start, mut state) = initial_state(data_str);
+    for (ph_start, ph_end) in find_placeholders(data_str, placeholders)? {
+        let (segment, mut next_state) = lex_range(data_str, start, ph_start, state)?;
+        for token in segment {
+            match token {
+                // The segment ends where the placeholder starts.
+                Token::Eof(d) => tokens.push(Token::Placeholder(TokenData {
+                    content: &data_str[ph_start..ph_end],
+                    ..d
+                })),
+                t => tokens.push(t),
+            }
+        }
+        for (idx, c) in data_str[ph_start..ph_end].char_indices() {
+            if c == '\n' {
+                next_state.line += 1;
+                next_state.last_line_end = ph_start + idx + 1;
+            }
+        }
+        next_state.last_token_end = ph_end;
+        state = next_state;
+        start = ph_end;
+    }
+    let (segment, _) = lex_range(data_str, start, data_str.len(), state)?;
+    tokens.extend(segment);
+    Ok(join_placeholders(data_str, tokens))
+}
+
+/// Joins placeholders with identifiers and placeholders they touch, so that e.g.
+/// `{{ director }}.backend` or `{{ prefix }}_backend` become a single placeholder.
+fn join_placeholders<'a>(data_str: &'a str, tokens: Vec<Token<'a>>) -> Vec<Token<'a>> {
+    let mut joined: Vec<Token<'a>> = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        if let (Some(prev), Some(next)) = (joined.last_mut(), token.data()) {
+            let joinable = |t: &Token| {
+                matches!(
+                    t,
+                    Token::Ident(_) | Token::BackendPropIdent(_) | Token::Placeholder(_)
+                )
+            };
+            let placeholder_involved =
+                matches!(prev, Token::Placeholder(_)) || matches!(token, Token::Placeholder(_));
+            if let Some(p) = prev.data().copied().filter(|p| {
+                placeholder_involved
+                    && joinable(prev)
+                    && joinable(&token)
+                    && p.offset + p.content.len() == next.offset
+            }) {
+                *prev = Token::Placeholder(TokenData {
+                    content: &data_str[p.offset..next.offset + next.content.len()],
+                    ..p
+                });
+                continue;
+            }
+        }
+        joined.push(token);
+    }
+    joined
+}
+
+/// Lexes `data_str[start..end]`, keeping positions relative to the whole string.
+fn lex_range(
+    data_str: &str,
+    start: usize,
+    end: usize,
+    state: LexerState,
+) -> Result<(Vec<Token<'_>>, LexerState), crate::error::E> {
+    let mut lex = Token::lexer_with_extras(&data_str[..end], state);
+    lex.bump(start);
+    let mut iter = TokenIter {
         lex,
         lex_done: false,
     };
-    iter.collect()
+    let tokens = (&mut iter).collect::<Result<Vec<_>, _>>()?;
+    Ok((tokens, iter.lex.extras))
+}
+
+fn find_placeholders(
+    data_str: &str,
+    placeholders: &[Delimiters],
+) -> Result<Vec<(usize, usize)>, crate::error::E> {
+    // Everything that may contain delimiters without them starting a placeholder.
+    const OPAQUE: [(&str, &str); 7] = [
+        ("\"\"\"", "\"\"\""),
+        ("{\"", "\"}"),
+        ("\"", "\""),
+        ("//", "\n"),
+        ("#", "\n"),
+        ("/*", "*/"),
+        ("C{", "}C"),
+    ];
+
+    let mut found = vec![];
+    let mut idx = 0;
+    while let Some(rest) = data_str.get(idx..).filter(|r| !r.is_empty()) {
+        let placeholder = placeholders
+            .iter()
+            .filter(|d| !d.open.is_empty() && rest.starts_with(&d.open))
+            .max_by_key(|d| d.open.len());
+        if let Some(d) = placeholder {
+            let Some(close) = rest[d.open.len()..].find(&d.close) else {
+                let (line, column) = line_column(data_str, idx);
+                return Err(crate::error::E::UnterminatedPlaceholder { line, column });
+            };
+            let end = idx + d.open.len() + close + d.close.len();
+            found.push((idx, end));
+            idx = end;
+            continue;
+        }
+
+        idx += match OPAQUE.iter().find(|(open, _)| rest.starts_with(open)) {
+            // An unterminated string or comment is left for the lexer to report.
+            Some((open, close)) => rest[open.len()..]
+                .find(close)
+                .map_or(rest.len(), |i| open.len() + i + close.len()),
+            None => rest.chars().next().map_or(1, char::len_utf8),
+        };
+    }
+    Ok(found)
+}
+
+fn line_column(data_str: &str, offset: usize) -> (usize, usize) {
+    let before = &data_str[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = offset - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, column)
 }
 
 pub fn lex_trivia(data_str: &str) -> Result<Vec<TriviaToken<'_>>, crate::error::E> {
@@ -15,14 +188,94 @@ pub fn lex_trivia(data_str: &str) -> Result<Vec<TriviaToken<'_>>, crate::error::
     lex.collect()
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+/// Length of the part of `trivia` that is on the line of the preceding token: whitespace and
+/// comments up to the first line break that is not inside a comment.
+pub fn same_line_trivia_len(trivia: &str) -> usize {
+    let mut rest = trivia;
+    loop {
+        let trimmed = rest.trim_start_matches([' ', '\t']);
+        let len = if trimmed.starts_with('#') || trimmed.starts_with("//") {
+            trimmed.find(['\r', '\n']).unwrap_or(trimmed.len())
+        } else if trimmed.starts_with("/*") {
+            trimmed.find("*/").map_or(trimmed.len(), |i| i + 2)
+        } else if trimmed.starts_with("C{") {
+            trimmed.find("}C").map_or(trimmed.len(), |i| i + 2)
+        } else {
+            return trivia.len() - trimmed.len();
+        };
+        rest = &trimmed[len..];
+    }
+}
+
+/// Returns the contents of a string literal without its quotes.
+pub fn string_value(literal: &str) -> &str {
+    let quotes = [("\"\"\"", "\"\"\""), ("{\"", "\"}"), ("\"", "\"")];
+    for (open, close) in quotes {
+        if literal.len() >= open.len() + close.len()
+            && literal.starts_with(open)
+            && literal.ends_with(close)
+        {
+            return &literal[open.len()..literal.len() - close.len()];
+        }
+    }
+    literal
+}
+
+#[derive(Copy, Clone, PartialEq)]
 pub struct TokenData<'a> {
     pub content: &'a str,
     pub line: usize,
     pub column: usize,
+    /// Byte offset of the token in the source.
+    pub offset: usize,
     pub pre_trivia: &'a str,
 }
 
+/// Only the content is printed, so that debug output of a syntax tree depends only on its shape.
+impl<'a> std::fmt::Debug for TokenData<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self.content))
+    }
+}
+
+/// Serialized with its position and the comments preceding it, see `docs/ast-json.md`.
+impl<'a> serde::Serialize for TokenData<'a> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{Error, SerializeStruct};
+
+        let comments: Vec<&str> = lex_trivia(self.pre_trivia)
+            .map_err(|e| S::Error::custom(format!("{e:?}")))?
+            .into_iter()
+            .filter_map(|t| match t {
+                TriviaToken::LineComment(s)
+                | TriviaToken::MultilineComment(s)
+                | TriviaToken::InlineCCode(s) => Some(s),
+                TriviaToken::Newline => None,
+            })
+            .collect();
+        let mut st = serializer.serialize_struct("Token", 5)?;
+        st.serialize_field("text", self.content)?;
+        st.serialize_field("line", &self.line)?;
+        st.serialize_field("column", &self.column)?;
+        st.serialize_field("offset", &self.offset)?;
+        st.serialize_field("comments", &comments)?;
+        st.end()
+    }
+}
+
+impl<'a> TokenData<'a> {
+    /// Creates a token that doesn't come from any source file.
+    pub fn synthetic(content: &'a str, pre_trivia: &'a str) -> Self {
+        Self {
+            content,
+            line: 0,
+            column: 0,
+            offset: 0,
+            pre_trivia,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct LexerState {
     line: usize,
@@ -54,9 +307,11 @@ impl<'a> Iterator for TokenIter<'a> {
             let final_trivia = &self.lex.source()[self.lex.extras.last_token_end..];
             let (line, column) = position(&self.lex, self.lex.source().len());
             let data = TokenData {
-                content: "",
+                // Empty, but pointing into the source like the content of other tokens.
+                content: &self.lex.source()[self.lex.source().len()..],
                 line,
                 column,
+                offset: self.lex.source().len(),
                 pre_trivia: final_trivia,
             };
             Some(Ok(Token::Eof(data)))
@@ -84,6 +339,7 @@ fn token_callback<'a>(lex: &mut Lexer<'a, Token<'a>>) -> Option<TokenData<'a>> {
         content: lex.slice(),
         line,
         column,
+        offset: start,
         pre_trivia,
     })
 }
@@ -283,9 +539,82 @@ pub enum Token<'a> {
     #[regex(r"(\r\n|\n|\r)", newline_callback)]
     Newline,
 
+    /// A template placeholder, only produced by `lex_with_placeholders`.
+    Placeholder(TokenData<'a>),
+
     Eof(TokenData<'a>),
 }
 
+impl<'a> Token<'a> {
+    /// Returns the token's data, or `None` for trivia, which the lexer never emits.
+    pub fn data(&self) -> Option<&TokenData<'a>> {
+        match self {
+            Token::Acl(d)
+            | Token::Vcl(d)
+            | Token::Import(d)
+            | Token::Include(d)
+            | Token::From(d)
+            | Token::Probe(d)
+            | Token::Backend(d)
+            | Token::None(d)
+            | Token::Sub(d)
+            | Token::Set(d)
+            | Token::Call(d)
+            | Token::Unset(d)
+            | Token::If(d)
+            | Token::Else(d)
+            | Token::ElseIf(d)
+            | Token::Return(d)
+            | Token::New(d)
+            | Token::Bool(d)
+            | Token::Number(d)
+            | Token::Duration(d)
+            | Token::Bytes(d)
+            | Token::String(d)
+            | Token::Ident(d)
+            | Token::BackendPropIdent(d)
+            | Token::Semicolon(d)
+            | Token::LBrace(d)
+            | Token::RBrace(d)
+            | Token::LParen(d)
+            | Token::RParen(d)
+            | Token::Negate(d)
+            | Token::Assign(d)
+            | Token::Plus(d)
+            | Token::Minus(d)
+            | Token::Multiply(d)
+            | Token::Divide(d)
+            | Token::Comma(d)
+            | Token::Or(d)
+            | Token::And(d)
+            | Token::Equals(d)
+            | Token::NotEquals(d)
+            | Token::Matches(d)
+            | Token::Greater(d)
+            | Token::Lesser(d)
+            | Token::GreaterEquals(d)
+            | Token::LesserEquals(d)
+            | Token::Increment(d)
+            | Token::Decrement(d)
+            | Token::ShiftLeft(d)
+            | Token::ShiftRight(d)
+            | Token::AddAssign(d)
+            | Token::SubtractAssign(d)
+            | Token::MultiplyAssign(d)
+            | Token::DivideAssign(d)
+            | Token::NotMatches(d)
+            | Token::Modulo(d)
+            | Token::BitwiseAnd(d)
+            | Token::BitwiseOr(d)
+            | Token::Placeholder(d)
+            | Token::Eof(d) => Some(d),
+            Token::LineComment | Token::MultilineComment | Token::InlineCCode | Token::Newline => {
+                None
+            }
+        }
+    }
+}
+
 #[derive(Logos, Debug, PartialEq, Copy, Clone)]
 #[logos(skip r"[ \t]+")]
 pub enum TriviaToken<'a> {
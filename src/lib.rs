@@ -0,0 +1,83 @@
+#![warn(clippy::unwrap_used)]
+#![warn(clippy::expect_used)]
+
+pub mod acl;
+pub mod ast;
+pub mod ast_emitter;
+pub mod builder;
+pub mod bundle;
+pub mod dump;
+pub mod emitter;
+pub mod error;
+pub mod lexer;
+pub mod markdown;
+pub mod newline;
+pub mod organize;
+pub mod parens;
+pub mod parser;
+pub mod resolver;
+pub mod split;
+pub mod style;
+pub mod verify;
+pub mod vtc;
+
+use std::{borrow::Cow, io::Write};
+
+use error::{E, R};
+use lexer::Delimiters;
+use parser::FragmentKind;
+use style::Style;
+
+/// Formats `data` as a complete VCL file, or as a fragment of the given kind.
+///
+/// Text between any of the `placeholders` delimiters is kept as is, see
+/// [`lexer::lex_with_placeholders`].
+pub fn process_vcl(
+    data: &str,
+    style: &Style,
+    fragment: Option<FragmentKind>,
+    base_indent: usize,
+    placeholders: &[Delimiters],
+    out: &mut dyn Write,
+) -> R {
+    let rewritten = rewrite(data, style, fragment, placeholders)?;
+    let data = &*rewritten;
+    let tokens = lexer::lex_with_placeholders(data, placeholders)?;
+    let mut emitter = ast_emitter::AstEmitter::new(out, style, base_indent).with_source(data);
+    match fragment {
+        Some(kind) => {
+            let fragment = parser::parse_fragment(&tokens, kind)?;
+            emitter.emit_fragment(&fragment)?;
+        }
+        None => {
+            let ast = parser::parse(&tokens)?;
+            emitter.emit(&ast)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the options of `style` that change more than the layout of `data`: reordering
+/// declarations, normalizing ACLs and normalizing parentheses. [`process_vcl`] formats the
+/// result, so this is what its output should be compared with.
+pub fn rewrite<'s>(
+    data: &'s str,
+    style: &Style,
+    fragment: Option<FragmentKind>,
+    placeholders: &[Delimiters],
+) -> Result<Cow<'s, str>, E> {
+    let mut rewritten = if style.organize {
+        organize::organize(data, fragment, placeholders)?
+    } else {
+        Cow::Borrowed(data)
+    };
+    if style.normalize_acls || style.sort_acls {
+        let normalized = acl::normalize(&rewritten, fragment, placeholders, style.sort_acls)?;
+        rewritten = Cow::Owned(normalized.into_owned());
+    }
+    if style.normalize_parentheses {
+        let normalized = parens::normalize(&rewritten, fragment, placeholders)?;
+        rewritten = Cow::Owned(normalized.into_owned());
+    }
+    Ok(rewritten)
+}
@@ -1,54 +1,441 @@
 #![warn(clippy::unwrap_used)]
 #![warn(clippy::expect_used)]
 
-mod ast;
-mod ast_emitter;
-mod emitter;
-mod error;
-mod lexer;
-mod parser;
+use std::{
+    io::{Read, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
-use std::io::{Read, Write};
-
-use clap::Parser as ClapParser;
-use error::R;
+use clap::{error::ErrorKind, ArgGroup, CommandFactory, Parser as ClapParser, Subcommand};
+use vcl_formatter::{
+    acl, bundle,
+    dump::{self, EmitFormat},
+    error::{E, R},
+    lexer::Delimiters,
+    markdown, newline,
+    parser::FragmentKind,
+    process_vcl,
+    resolver::IncludeResolver,
+    rewrite, split,
+    style::Style,
+    verify, vtc,
+};
 
 /// Formatter for VCL code
 #[derive(ClapParser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(group(ArgGroup::new("output_mode").args(["check", "write"])))]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// VCL file to format
-    #[arg()]
-    file: String,
+    #[arg(required = true)]
+    file: Option<String>,
+
+    /// Format of FILE, detected from its extension by default
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    input_format: Option<InputFormat>,
+
+    /// Format a fragment (e.g. an included file) instead of a complete VCL file
+    #[arg(long, value_enum)]
+    fragment: Option<FragmentKind>,
+
+    /// Number of indentation levels to add to every formatted line
+    #[arg(long, default_value_t = 0)]
+    base_indent: usize,
+
+    /// Check if the input is formatted instead of printing it, fail if it isn't
+    #[arg(long)]
+    check: bool,
+
+    /// Write formatted output back to the input file(s)
+    #[arg(short, long)]
+    write: bool,
+
+    /// Also process all files included from FILE, transitively
+    #[arg(long, requires = "output_mode")]
+    follow_includes: bool,
+
+    /// Print all files included from FILE, transitively
+    #[arg(long, conflicts_with = "output_mode")]
+    list_includes: bool,
+
+    /// Additional directory to search for included files
+    #[arg(short = 'I', long = "include-path", value_name = "DIR")]
+    include_paths: Vec<PathBuf>,
+
+    /// Re-parse formatted output and refuse to write it if the code itself changed
+    #[arg(long)]
+    verify: bool,
+
+    /// Format the output a second time and refuse to write it if that changes anything
+    #[arg(long)]
+    verify_idempotent: bool,
+
+    /// Print the syntax tree or tokens of FILE instead of formatting it
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    #[arg(conflicts_with_all = ["output_mode", "follow_includes", "list_includes"])]
+    emit: Option<EmitFormat>,
+
+    /// Keep Jinja/Go template (`{{ }}`, `{% %}`, `{# #}`) and envsubst (`${ }`) placeholders as is
+    #[arg(long)]
+    template: bool,
+
+    /// Keep text between OPEN and CLOSE as is, e.g. `--placeholder '<< >>'`
+    #[arg(long, value_name = "OPEN CLOSE", value_parser = parse_delimiters)]
+    placeholder: Vec<Delimiters>,
+
+    #[command(flatten)]
+    style: Style,
+}
+
+impl Args {
+    fn placeholders(&self) -> Vec<Delimiters> {
+        let mut placeholders = self.placeholder.clone();
+        if self.template {
+            placeholders.extend(Delimiters::template_defaults());
+        }
+        placeholders
+    }
+}
+
+fn parse_delimiters(value: &str) -> Result<Delimiters, String> {
+    match value.split_whitespace().collect::<Vec<_>>()[..] {
+        [open, close] => Ok(Delimiters {
+            open: open.to_string(),
+            close: close.to_string(),
+        }),
+        _ => Err("expected an opening and a closing delimiter separated by a space".to_string()),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+enum InputFormat {
+    /// VCL code
+    Vcl,
+    /// A varnishtest script, only VCL passed to `-vcl` and `-vcl+backend` is formatted
+    Vtc,
+    /// A Markdown document, only fenced code blocks tagged `vcl` are formatted
+    Markdown,
+}
+
+impl InputFormat {
+    fn detect(file: &str) -> Self {
+        match Path::new(file).extension() {
+            Some(ext) if ext == "vtc" => InputFormat::Vtc,
+            Some(ext) if ext == "md" || ext == "markdown" => InputFormat::Markdown,
+            _ => InputFormat::Vcl,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inline all included files into a single formatted VCL file
+    Bundle {
+        /// Entrypoint VCL file
+        file: String,
+
+        /// Precede inlined code with `# from: path:line` comments
+        #[arg(long)]
+        markers: bool,
+
+        /// Additional directory to search for included files
+        #[arg(short = 'I', long = "include-path", value_name = "DIR")]
+        include_paths: Vec<PathBuf>,
+
+        #[command(flatten)]
+        style: Style,
+    },
+    /// Move subs, backends, probes and ACLs into separate files included from the main file
+    Split {
+        /// VCL file to split
+        file: String,
+
+        /// Directory to write the main file and the included files to
+        #[arg(short, long, value_name = "DIR")]
+        out_dir: PathBuf,
 
-    /// Number of spaces to use for indentation
-    #[arg(short, long, default_value_t = 4)]
-    indent: usize,
+        /// Prefix for paths in generated include statements
+        #[arg(long, default_value = "")]
+        include_prefix: String,
+
+        #[command(flatten)]
+        style: Style,
+    },
 }
 
 fn main() -> R {
     let args = Args::parse();
-    let data = if args.file == "-" {
+    match &args.command {
+        Some(Command::Bundle {
+            file,
+            style,
+            markers,
+            include_paths,
+        }) => {
+            let resolver = IncludeResolver::new(include_paths.clone());
+            let source = read_input(file)?;
+            let project = resolver.resolve(Path::new(file), source.clone())?;
+            let mut bundled = vec![];
+            bundle::bundle(&project, style, *markers, &mut bundled)?;
+            let bundled = std::str::from_utf8(&bundled)?;
+            let literals = newline::literals(bundled, &[]);
+            let bundled = newline::finish(&source, bundled, style, &literals);
+            std::io::stdout()
+                .lock()
+                .write_all(bundled.as_bytes())
+                .map_err(E::FailedToWriteOutput)
+        }
+        Some(Command::Split {
+            file,
+            style,
+            out_dir,
+            include_prefix,
+        }) => {
+            let main_name = Path::new(file)
+                .file_name()
+                .filter(|_| file != "-")
+                .map_or("main.vcl".into(), |n| n.to_string_lossy());
+            let source = read_input(file)?;
+            let files = split::split(&source, &main_name, include_prefix, style)?;
+            std::fs::create_dir_all(out_dir).map_err(E::FailedToWriteOutput)?;
+            for f in &files {
+                let path = out_dir.join(&f.name);
+                if path.exists() {
+                    return Err(E::OutputExists(path.display().to_string()));
+                }
+            }
+            for f in &files {
+                let contents = std::str::from_utf8(&f.contents)?;
+                let literals = newline::literals(contents, &[]);
+                let contents = newline::finish(&source, contents, style, &literals);
+                std::fs::write(out_dir.join(&f.name), contents).map_err(E::FailedToWriteOutput)?;
+            }
+            Ok(())
+        }
+        None => format(&args),
+    }
+}
+
+fn read_input(file: &str) -> Result<String, E> {
+    let data = if file == "-" {
         let mut buf = Vec::new();
         std::io::stdin()
             .lock()
             .read_to_end(&mut buf)
-            .map_err(crate::error::E::FailedToReadInput)?;
+            .map_err(E::FailedToReadInput)?;
         buf
     } else {
-        std::fs::read(args.file.as_str()).map_err(crate::error::E::FailedToReadInput)?
+        std::fs::read(file).map_err(E::FailedToReadInput)?
     };
+    Ok(std::str::from_utf8(&data)?.to_string())
+}
 
-    let data_str = std::str::from_utf8(&data)?;
-    let mut stdout = std::io::stdout().lock();
-    process_vcl(data_str, args.indent, &mut stdout)?;
-    Ok(())
+fn format(args: &Args) -> R {
+    let Some(file) = args.file.as_deref() else {
+        unreachable!("clap requires FILE when no subcommand is given");
+    };
+    let input_format = args
+        .input_format
+        .unwrap_or_else(|| InputFormat::detect(file));
+    if (args.follow_includes || args.list_includes) && input_format != InputFormat::Vcl {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "includes can only be followed from VCL files, pass --input-format vcl to read FILE as VCL",
+            )
+            .exit();
+    }
+    let data_str = read_input(file)?;
+    if let Some(format) = args.emit {
+        let mut stdout = std::io::stdout().lock();
+        let placeholders = args.placeholders();
+        return dump::emit(format, &data_str, args.fragment, &placeholders, &mut stdout);
+    }
+
+    let mut unformatted = vec![];
+    if args.follow_includes || args.list_includes {
+        let resolver =
+            IncludeResolver::new(args.include_paths.clone()).with_placeholders(args.placeholders());
+        let project = resolver.resolve(Path::new(file), data_str)?;
+        if args.list_includes {
+            let mut stdout = std::io::stdout().lock();
+            for unit in &project.units {
+                writeln!(stdout, "{}", unit.path.display()).map_err(E::FailedToWriteOutput)?;
+                for include in &unit.includes {
+                    writeln!(
+                        stdout,
+                        "    line {}: \"{}\" -> {}",
+                        include.line,
+                        include.name,
+                        include.path.display()
+                    )
+                    .map_err(E::FailedToWriteOutput)?;
+                }
+            }
+            return Ok(());
+        }
+
+        for (idx, unit) in project.units.iter().enumerate() {
+            let path = unit.path.display().to_string();
+            let fragment = if idx == 0 {
+                args.fragment
+            } else {
+                Some(FragmentKind::Auto)
+            };
+            let formatted = format_source(args, &path, &unit.source, fragment, args.base_indent)
+                .map_err(|e| E::InFile {
+                    path: path.clone(),
+                    error: Box::new(e),
+                })?;
+            output(
+                args,
+                InputFormat::Vcl,
+                &path,
+                &unit.source,
+                &formatted,
+                &mut unformatted,
+            )?;
+        }
+    } else {
+        let formatted = match input_format {
+            InputFormat::Vcl => {
+                format_source(args, file, &data_str, args.fragment, args.base_indent)?
+            }
+            InputFormat::Vtc => vtc::format(&data_str, args.style.indent, |block, base_indent| {
+                format_source(args, file, block, Some(FragmentKind::TopLevel), base_indent)
+            })?,
+            // Snippets in documentation are often just a few statements or an expression.
+            InputFormat::Markdown => markdown::format(&data_str, |block| {
+                let fragment = args.fragment.or(Some(FragmentKind::Auto));
+                format_source(args, file, block, fragment, args.base_indent)
+            })?,
+        };
+        output(
+            args,
+            input_format,
+            file,
+            &data_str,
+            &formatted,
+            &mut unformatted,
+        )?;
+    }
+
+    if unformatted.is_empty() {
+        Ok(())
+    } else {
+        Err(E::CheckFailed(unformatted))
+    }
+}
+
+fn format_source(
+    args: &Args,
+    path: &str,
+    source: &str,
+    fragment: Option<FragmentKind>,
+    base_indent: usize,
+) -> Result<Vec<u8>, E> {
+    let placeholders = args.placeholders();
+    let mut formatted = vec![];
+    process_vcl(
+        source,
+        &args.style,
+        fragment,
+        base_indent,
+        &placeholders,
+        &mut formatted,
+    )?;
+    if args.style.normalize_acls || args.style.sort_acls {
+        for warning in acl::check(source, fragment, &placeholders)? {
+            if path == "-" {
+                eprintln!("Warning: {warning}");
+            } else {
+                eprintln!("Warning: {path}: {warning}");
+            }
+        }
+    }
+    if args.verify {
+        let expected = rewrite(source, &args.style, fragment, &placeholders)?;
+        verify::verify(
+            &expected,
+            std::str::from_utf8(&formatted)?,
+            fragment,
+            &placeholders,
+        )?;
+    }
+    if args.verify_idempotent {
+        let first = std::str::from_utf8(&formatted)?;
+        let mut second = vec![];
+        process_vcl(
+            first,
+            &args.style,
+            fragment,
+            base_indent,
+            &placeholders,
+            &mut second,
+        )
+        .map_err(|e| E::InvalidOutput(Box::new(e)))?;
+        verify::verify_idempotent(first, std::str::from_utf8(&second)?)?;
+    }
+    Ok(formatted)
+}
+
+/// Byte ranges of `formatted` whose line breaks are kept as written, see [`newline::literals`].
+fn literals(
+    input_format: InputFormat,
+    formatted: &str,
+    placeholders: &[Delimiters],
+) -> Result<Vec<Range<usize>>, E> {
+    let blocks: Vec<Range<usize>> = match input_format {
+        InputFormat::Vcl => return Ok(newline::literals(formatted, placeholders)),
+        InputFormat::Vtc => vtc::vcl_blocks(formatted)?
+            .iter()
+            .map(|b| b.start..b.end)
+            .collect(),
+        InputFormat::Markdown => markdown::vcl_blocks(formatted)
+            .iter()
+            .map(|b| b.start..b.end)
+            .collect(),
+    };
+    Ok(blocks
+        .into_iter()
+        .flat_map(|b| {
+            newline::literals(&formatted[b.clone()], placeholders)
+                .into_iter()
+                .map(move |r| r.start + b.start..r.end + b.start)
+        })
+        .collect())
 }
 
-fn process_vcl(data: &str, indent: usize, out: &mut dyn Write) -> R {
-    let tokens = lexer::lex(data)?;
-    let ast = parser::parse(&tokens)?;
-    let mut emitter = ast_emitter::AstEmitter::new(out, indent);
-    emitter.emit(&ast)?;
+fn output(
+    args: &Args,
+    input_format: InputFormat,
+    path: &str,
+    original: &str,
+    formatted: &[u8],
+    unformatted: &mut Vec<String>,
+) -> R {
+    let formatted = std::str::from_utf8(formatted)?;
+    let literals = literals(input_format, formatted, &args.placeholders())?;
+    let formatted = newline::finish(original, formatted, &args.style, &literals);
+    let changed = formatted != original;
+    if args.check {
+        if changed {
+            unformatted.push(path.to_string());
+        }
+    } else if args.write && path != "-" {
+        if changed {
+            std::fs::write(path, formatted).map_err(E::FailedToWriteOutput)?;
+        }
+    } else {
+        std::io::stdout()
+            .lock()
+            .write_all(formatted.as_bytes())
+            .map_err(E::FailedToWriteOutput)?;
+    }
     Ok(())
 }
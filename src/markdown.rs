@@ -0,0 +1,119 @@
+use crate::error::E;
+
+/// A fenced code block tagged `vcl` in a Markdown document.
+pub struct VclBlock {
+    /// Byte range of the block contents, from the line after the opening fence to the
+    /// closing fence (or the end of the document if the block is not closed).
+    pub start: usize,
+    pub end: usize,
+    /// Line of the opening fence.
+    pub line: usize,
+    /// Number of spaces the opening fence is indented by.
+    pub fence_indent: usize,
+}
+
+/// Finds fenced code blocks (```` ``` ```` or `~~~`) whose info string starts with `vcl`.
+pub fn vcl_blocks(source: &str) -> Vec<VclBlock> {
+    let mut blocks = vec![];
+    let mut lines = lines_with_offsets(source).enumerate();
+    while let Some((line_idx, (offset, line))) = lines.next() {
+        let Some((fence, fence_indent, info)) = opening_fence(line) else {
+            continue;
+        };
+        let start = offset + line.len();
+        let mut end = source.len();
+        for (_, (offset, line)) in lines.by_ref() {
+            if is_closing_fence(line, fence) {
+                end = offset;
+                break;
+            }
+        }
+        if info.split_whitespace().next() == Some("vcl") {
+            blocks.push(VclBlock {
+                start,
+                end,
+                line: line_idx + 1,
+                fence_indent,
+            });
+        }
+    }
+    blocks
+}
+
+/// Replaces the contents of every `vcl` code block with the result of `format_block`, which is
+/// given the contents without the indentation of the fence. Everything else is kept as is.
+pub fn format(
+    source: &str,
+    mut format_block: impl FnMut(&str) -> Result<Vec<u8>, E>,
+) -> Result<Vec<u8>, E> {
+    let mut out = vec![];
+    let mut copied = 0;
+    for block in vcl_blocks(source) {
+        let contents = &source[block.start..block.end];
+        if contents.trim().is_empty() {
+            continue;
+        }
+        let unindented: String = contents
+            .split_inclusive('\n')
+            .map(|l| strip_spaces(l, block.fence_indent))
+            .collect();
+        let formatted = format_block(&unindented).map_err(|e| E::InBlock {
+            line: block.line,
+            error: Box::new(e),
+        })?;
+        out.extend_from_slice(&source.as_bytes()[copied..block.start]);
+        for line in formatted.split_inclusive(|b| *b == b'\n') {
+            if line != b"\n" {
+                out.extend_from_slice(" ".repeat(block.fence_indent).as_bytes());
+            }
+            out.extend_from_slice(line);
+        }
+        copied = block.end;
+    }
+    out.extend_from_slice(&source.as_bytes()[copied..]);
+    Ok(out)
+}
+
+fn lines_with_offsets(source: &str) -> impl Iterator<Item = (usize, &str)> {
+    source.split_inclusive('\n').scan(0, |offset, line| {
+        let start = *offset;
+        *offset += line.len();
+        Some((start, line))
+    })
+}
+
+/// Returns the fence, its indentation and the info string if `line` opens a code block.
+fn opening_fence(line: &str) -> Option<(&str, usize, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let rest = &line[indent..];
+    let fence_char = rest.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let fence_len = rest.len() - rest.trim_start_matches(fence_char).len();
+    if fence_len < 3 {
+        return None;
+    }
+    let info = rest[fence_len..].trim();
+    if fence_char == '`' && info.contains('`') {
+        return None;
+    }
+    Some((&rest[..fence_len], indent, info))
+}
+
+fn is_closing_fence(line: &str, fence: &str) -> bool {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return false;
+    }
+    let Some(fence_char) = fence.chars().next() else {
+        return false;
+    };
+    let rest = trimmed.trim_start_matches(fence_char);
+    trimmed.len() - rest.len() >= fence.len() && rest.trim().is_empty()
+}
+
+fn strip_spaces(line: &str, max: usize) -> &str {
+    let spaces = line.len() - line.trim_start_matches(' ').len();
+    &line[spaces.min(max)..]
+}
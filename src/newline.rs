@@ -0,0 +1,103 @@
+use std::ops::Range;
+
+use crate::{
+    lexer::{self, lex_trivia, Delimiters, Token, TriviaToken, BOM},
+    style::{NewlineStyle, Style},
+};
+
+/// Line ending used by most lines of `source`, `\n` if it has no line breaks or several are
+/// used equally often.
+pub fn dominant(source: &str) -> &'static str {
+    let crlf = source.matches("\r\n").count();
+    let cr = source.matches('\r').count() - crlf;
+    let lf = source.matches('\n').count() - crlf;
+    if crlf > lf && crlf > cr {
+        "\r\n"
+    } else if cr > lf && cr > crlf {
+        "\r"
+    } else {
+        "\n"
+    }
+}
+
+/// Line ending that `style` asks for in output formatted from `source`.
+pub fn line_ending(style: NewlineStyle, source: &str) -> &'static str {
+    match style {
+        NewlineStyle::Auto => dominant(source),
+        NewlineStyle::Lf => "\n",
+        NewlineStyle::Crlf => "\r\n",
+        NewlineStyle::Native if cfg!(windows) => "\r\n",
+        NewlineStyle::Native => "\n",
+    }
+}
+
+/// Byte ranges of `vcl` taken up by text that is kept as written even if it spans several lines:
+/// long strings, block comments, inline C code and template placeholders. Empty if `vcl` cannot
+/// be lexed.
+pub fn literals(vcl: &str, placeholders: &[Delimiters]) -> Vec<Range<usize>> {
+    let Ok(tokens) = lexer::lex_with_placeholders(vcl, placeholders) else {
+        return vec![];
+    };
+    let mut found = vec![];
+    for data in tokens.iter().filter_map(Token::data) {
+        let trivia_start = data.offset - data.pre_trivia.len();
+        for trivia in lex_trivia(data.pre_trivia).unwrap_or_default() {
+            if let TriviaToken::MultilineComment(s) | TriviaToken::InlineCCode(s) = trivia {
+                let start =
+                    trivia_start + (s.as_ptr() as usize - data.pre_trivia.as_ptr() as usize);
+                found.push(start..start + s.len());
+            }
+        }
+        if data.content.contains(['\r', '\n']) {
+            found.push(data.offset..data.offset + data.content.len());
+        }
+    }
+    found
+}
+
+/// Applies the options of `style` that concern the file as a whole to `formatted`, the output
+/// for `original`: every line break outside of `literals` (byte ranges of `formatted`, see
+/// [`literals`]) becomes the line ending chosen by `newline_style`, the final line break is added
+/// or removed, and a byte order mark at the start of `original` is kept.
+///
+/// The formatter itself always writes `\n` and drops the byte order mark, so this is meant for
+/// the complete output, after VCL embedded in other files has been put back.
+pub fn finish(original: &str, formatted: &str, style: &Style, literals: &[Range<usize>]) -> String {
+    let ending = line_ending(style.newline_style, original);
+    let bom_len = if formatted.starts_with(BOM) {
+        BOM.len()
+    } else {
+        0
+    };
+
+    let mut out = String::with_capacity(formatted.len() + formatted.len() / 16);
+    if original.starts_with(BOM) {
+        out.push_str(BOM);
+    }
+    let start = out.len();
+    let mut copied = bom_len;
+    let mut idx = bom_len;
+    while let Some(found) = formatted[idx..].find(['\r', '\n']) {
+        idx += found;
+        let len = if formatted[idx..].starts_with("\r\n") {
+            2
+        } else {
+            1
+        };
+        if !literals.iter().any(|r| r.contains(&idx)) {
+            out.push_str(&formatted[copied..idx]);
+            out.push_str(ending);
+            copied = idx + len;
+        }
+        idx += len;
+    }
+    out.push_str(&formatted[copied..]);
+
+    let body_len = out.trim_end_matches(['\r', '\n']).len();
+    if !style.final_newline {
+        out.truncate(body_len);
+    } else if body_len == out.len() && body_len > start {
+        out.push_str(ending);
+    }
+    out
+}
@@ -0,0 +1,184 @@
+use std::borrow::Cow;
+
+use crate::{
+    ast::TopLevelDeclaration,
+    ast_emitter::{directives, Directive},
+    error::E,
+    lexer::{self, same_line_trivia_len, Delimiters, TokenData},
+    parser::{self, FragmentKind},
+};
+
+/// Subs called by Varnish, in the order a request goes through them.
+const LIFECYCLE_SUBS: &[&str] = &[
+    "vcl_init",
+    "vcl_recv",
+    "vcl_pipe",
+    "vcl_pass",
+    "vcl_hash",
+    "vcl_purge",
+    "vcl_miss",
+    "vcl_hit",
+    "vcl_deliver",
+    "vcl_synth",
+    "vcl_backend_fetch",
+    "vcl_backend_response",
+    "vcl_backend_error",
+    "vcl_fini",
+];
+
+const IMPORTS: usize = 1;
+const INCLUDES: usize = 2;
+
+/// A top-level declaration together with the comments that belong to it.
+struct Chunk<'s> {
+    /// Comments before the declaration.
+    leading: Cow<'s, str>,
+    code: &'s str,
+    /// Comments on the line the declaration ends on.
+    trailing: &'s str,
+    /// Where the declaration goes, `None` if nothing may be moved across it.
+    rank: Option<(usize, usize)>,
+    /// Module and `from` path of an import.
+    import: Option<(&'s str, Option<&'s str>)>,
+}
+
+/// Reorders top-level declarations into a canonical layout: the `vcl` version, imports (sorted
+/// and deduplicated), includes, probes, backends, ACLs, then subs, with Varnish's own subs last in
+/// the order they are called. Declarations of the same kind, includes in particular, keep their
+/// relative order, and comments move with the declaration they precede or end the line of.
+///
+/// Nothing is moved across top-level placeholders or code excluded by `vclfmt: off`. Fragments
+/// that are not top-level declarations, and code that does not parse, are returned as is.
+pub fn organize<'s>(
+    source: &'s str,
+    fragment: Option<FragmentKind>,
+    placeholders: &[Delimiters],
+) -> Result<Cow<'s, str>, E> {
+    if matches!(
+        fragment,
+        Some(FragmentKind::Statements | FragmentKind::Expression)
+    ) {
+        return Ok(Cow::Borrowed(source));
+    }
+    let Ok(tokens) = lexer::lex_with_placeholders(source, placeholders) else {
+        return Ok(Cow::Borrowed(source));
+    };
+    let Ok(sf) = parser::parse(&tokens) else {
+        return Ok(Cow::Borrowed(source));
+    };
+
+    let mut chunks = vec![];
+    let mut off = false;
+    for (idx, decl) in sf.declarations.iter().enumerate() {
+        let first = decl.first_token();
+        let next = sf
+            .declarations
+            .get(idx + 1)
+            .map_or(&sf.eof, TopLevelDeclaration::first_token);
+        let trivia_start = first.offset - first.pre_trivia.len();
+        let leading_start = match idx {
+            0 => trivia_start,
+            _ => trivia_start + same_line_trivia_len(first.pre_trivia),
+        };
+        let next_trivia_start = next.offset - next.pre_trivia.len();
+
+        let found = directives(first)?;
+        let barrier = off || found.contains(&Directive::Off) || found.contains(&Directive::On);
+        for d in found {
+            match d {
+                Directive::Off => off = true,
+                Directive::On => off = false,
+                Directive::Skip => {}
+            }
+        }
+
+        chunks.push(Chunk {
+            leading: Cow::Borrowed(&source[leading_start..first.offset]),
+            code: &source[first.offset..next_trivia_start],
+            trailing: &source
+                [next_trivia_start..next_trivia_start + same_line_trivia_len(next.pre_trivia)],
+            rank: if barrier { None } else { rank(decl) },
+            import: match decl {
+                TopLevelDeclaration::Import { name, from, .. } => Some((
+                    text(source, name),
+                    from.as_ref().map(|f| text(source, &f.value)),
+                )),
+                _ => None,
+            },
+        });
+    }
+
+    let mut organized = vec![];
+    let mut segment = vec![];
+    for chunk in chunks {
+        if chunk.rank.is_some() {
+            segment.push(chunk);
+        } else {
+            flush_segment(&mut segment, &mut organized);
+            organized.push(chunk);
+        }
+    }
+    flush_segment(&mut segment, &mut organized);
+
+    // Blank lines are redistributed: none between imports and between includes, one elsewhere.
+    let mut out = String::with_capacity(source.len());
+    let mut prev_group = None;
+    for chunk in &organized {
+        let group = chunk.rank.map(|(group, _)| group);
+        if !out.is_empty() {
+            out.push_str(match group {
+                Some(IMPORTS | INCLUDES) if group == prev_group => "\n",
+                _ => "\n\n",
+            });
+        }
+        prev_group = group;
+        out.push_str(chunk.leading.trim_start());
+        out.push_str(chunk.code);
+        out.push_str(chunk.trailing);
+    }
+    let eof_trivia_start = sf.eof.offset - sf.eof.pre_trivia.len();
+    out.push_str(&source[eof_trivia_start + same_line_trivia_len(sf.eof.pre_trivia)..]);
+    Ok(Cow::Owned(out))
+}
+
+/// Sorts `segment` into `organized`, dropping duplicate imports but keeping their comments.
+fn flush_segment<'s>(segment: &mut Vec<Chunk<'s>>, organized: &mut Vec<Chunk<'s>>) {
+    segment.sort_by_key(|c| (c.rank, c.import));
+    let mut last_import = None;
+    for chunk in segment.drain(..) {
+        if chunk.import.is_some() && chunk.import == last_import {
+            let mut comments = chunk.leading.trim().to_string();
+            if !chunk.trailing.trim().is_empty() {
+                comments = format!("{comments}\n{}", chunk.trailing.trim());
+            }
+            if let (false, Some(kept)) = (comments.trim().is_empty(), organized.last_mut()) {
+                kept.leading = Cow::Owned(format!("{}{}\n", kept.leading, comments.trim()));
+            }
+            continue;
+        }
+        last_import = chunk.import;
+        organized.push(chunk);
+    }
+}
+
+fn rank(decl: &TopLevelDeclaration) -> Option<(usize, usize)> {
+    let rank = match decl {
+        TopLevelDeclaration::VclVersion { .. } => (0, 0),
+        TopLevelDeclaration::Import { .. } => (IMPORTS, 0),
+        TopLevelDeclaration::Include(_) => (INCLUDES, 0),
+        TopLevelDeclaration::Probe { .. } => (3, 0),
+        TopLevelDeclaration::Backend(_) => (4, 0),
+        TopLevelDeclaration::Acl { .. } => (5, 0),
+        TopLevelDeclaration::Sub { name, .. } => {
+            let builtin = LIFECYCLE_SUBS.iter().position(|s| *s == name.content);
+            (6, builtin.map_or(0, |i| i + 1))
+        }
+        TopLevelDeclaration::Placeholder { .. } => return None,
+    };
+    Some(rank)
+}
+
+/// Contents of `token` borrowed from `source` rather than from the token list.
+fn text<'s>(source: &'s str, token: &TokenData) -> &'s str {
+    &source[token.offset..token.offset + token.content.len()]
+}
@@ -0,0 +1,224 @@
+use std::borrow::Cow;
+
+use crate::{
+    ast::*,
+    ast_emitter::kept_as_written,
+    error::E,
+    lexer::{self, Delimiters, TokenData},
+    parser::{self, binary_precedence, precedence, FragmentKind},
+};
+
+/// Where an expression appears, which decides whether it needs parentheses.
+#[derive(Copy, Clone)]
+enum Context<'t> {
+    /// Anywhere parentheses are never needed, e.g. an `if` condition or a function argument.
+    Free,
+    /// The operand of `!`.
+    Negated,
+    /// An operand of the binary operator `op`.
+    Operand { op: &'t str, min_precedence: u8 },
+}
+
+/// A change to the source: `len` bytes at `offset` are replaced with `text`.
+struct Edit {
+    offset: usize,
+    len: usize,
+    text: &'static str,
+}
+
+/// Rewrites expressions in `source` with parentheses where the parser needs them or where they
+/// make mixed operators clearer, and nowhere else. Clarifying parentheses go around `&&` inside
+/// `||`, arithmetic inside comparisons, and comparisons and other binary expressions after `!`.
+/// Parentheses are decided with the parser's precedence table, so the meaning does not change.
+/// Code that `vclfmt` directives keep as written is left alone.
+pub fn normalize<'s>(
+    source: &'s str,
+    fragment: Option<FragmentKind>,
+    placeholders: &[Delimiters],
+) -> Result<Cow<'s, str>, E> {
+    let tokens = lexer::lex_with_placeholders(source, placeholders)?;
+    let parsed = match fragment {
+        Some(kind) => parser::parse_fragment(&tokens, kind)?,
+        None => Fragment::TopLevel(parser::parse(&tokens)?),
+    };
+
+    let mut edits = vec![];
+    match &parsed {
+        Fragment::TopLevel(sf) => {
+            let kept = kept_as_written(&sf.declarations, TopLevelDeclaration::first_token)?;
+            for (decl, kept) in sf.declarations.iter().zip(kept) {
+                if kept {
+                    continue;
+                }
+                match decl {
+                    TopLevelDeclaration::Backend(BackendData::Defined { properties, .. })
+                    | TopLevelDeclaration::Probe { properties, .. } => {
+                        backend_properties(properties, &mut edits)
+                    }
+                    TopLevelDeclaration::Sub { statements: s, .. } => statements(s, &mut edits)?,
+                    _ => {}
+                }
+            }
+        }
+        Fragment::Statements { statements: s, .. } => statements(s, &mut edits)?,
+        Fragment::Expression { expr, .. } => expression(expr, Context::Free, &mut edits),
+    }
+    if edits.is_empty() {
+        return Ok(Cow::Borrowed(source));
+    }
+
+    // Insertions go before a removal at the same offset.
+    edits.sort_by_key(|e| (e.offset, e.len));
+    let mut out = String::with_capacity(source.len() + edits.len());
+    let mut copied = 0;
+    let is_word = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.');
+    for edit in edits {
+        out.push_str(&source[copied..edit.offset]);
+        copied = edit.offset + edit.len;
+        // A removed parenthesis must not join the tokens around it, as in `if(a)` or `-(1)`.
+        if edit.len > 0 && out.ends_with(is_word) && source[copied..].starts_with(is_word) {
+            out.push(' ');
+        }
+        out.push_str(edit.text);
+    }
+    out.push_str(&source[copied..]);
+    Ok(Cow::Owned(out))
+}
+
+fn backend_properties(properties: &[BackendProperty], edits: &mut Vec<Edit>) {
+    for p in properties {
+        match &p.value {
+            BackendValue::Expression { expr, .. } => expression(expr, Context::Free, edits),
+            BackendValue::Composite { properties, .. } => backend_properties(properties, edits),
+            BackendValue::StringList { .. } => {}
+        }
+    }
+}
+
+fn statements(statements: &[Statement], edits: &mut Vec<Edit>) -> Result<(), E> {
+    let kept = kept_as_written(statements, Statement::first_token)?;
+    for (st, kept) in statements.iter().zip(kept) {
+        if kept {
+            continue;
+        }
+        match st {
+            Statement::Set { expr, .. } => expression(expr, Context::Free, edits),
+            Statement::IdentCall { expr, .. } => ident_call(expr, edits),
+            Statement::New { value, .. } => ident_call(value, edits),
+            Statement::If {
+                condition,
+                body,
+                elseifs,
+                else_st,
+                ..
+            } => {
+                expression(condition, Context::Free, edits);
+                self::statements(body, edits)?;
+                for ei in elseifs {
+                    expression(&ei.condition, Context::Free, edits);
+                    self::statements(&ei.body, edits)?;
+                }
+                if let Some(e) = else_st {
+                    self::statements(&e.body, edits)?;
+                }
+            }
+            Statement::Return {
+                args: Some(args), ..
+            } => {
+                for (arg, _) in args.args.iter() {
+                    expression(arg, Context::Free, edits);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn ident_call(call: &IdentCallExpression, edits: &mut Vec<Edit>) {
+    for (arg, _) in call.args.iter() {
+        match arg {
+            FunctionCallArg::Named { value, .. } => expression(value, Context::Free, edits),
+            FunctionCallArg::Positional(e) => expression(e, Context::Free, edits),
+        }
+    }
+}
+
+fn expression(expr: &Expression, context: Context, edits: &mut Vec<Edit>) {
+    let mut inner = expr;
+    let mut parens = vec![];
+    while let Expression::Parenthesized {
+        lparen,
+        expr,
+        rparen,
+    } = inner
+    {
+        parens.push((lparen, rparen));
+        inner = expr;
+    }
+
+    // Only the outermost of nested parentheses is kept.
+    let wanted = needs_parentheses(inner, context);
+    for (idx, (lparen, rparen)) in parens.iter().enumerate() {
+        if !wanted || idx > 0 {
+            edits.push(remove(lparen));
+            edits.push(remove(rparen));
+        }
+    }
+    if wanted && parens.is_empty() {
+        let last = inner.last_token();
+        edits.push(Edit {
+            offset: inner.first_token().offset,
+            len: 0,
+            text: "(",
+        });
+        edits.push(Edit {
+            offset: last.offset + last.content.len(),
+            len: 0,
+            text: ")",
+        });
+    }
+
+    match inner {
+        Expression::Neg { expr, .. } => expression(expr, Context::Negated, edits),
+        Expression::Binary { left, op, right } => {
+            let p = binary_precedence(op.content).unwrap_or(0);
+            let operand = |min_precedence| Context::Operand {
+                op: op.content,
+                min_precedence,
+            };
+            expression(left, operand(p), edits);
+            expression(right, operand(p + 1), edits);
+        }
+        Expression::IdentCall(call) => ident_call(call, edits),
+        Expression::Ident(_) | Expression::Literal(_) | Expression::Parenthesized { .. } => {}
+    }
+}
+
+fn needs_parentheses(expr: &Expression, context: Context) -> bool {
+    match context {
+        Context::Free => false,
+        // `!a == b` negates the whole comparison, which is easy to misread.
+        Context::Negated => matches!(expr, Expression::Binary { .. }),
+        Context::Operand { op, min_precedence } => {
+            precedence(expr) < min_precedence
+                || matches!(expr, Expression::Binary { op: inner, .. } if clarifies(op, inner.content))
+        }
+    }
+}
+
+/// Whether an `inner` operation inside an `outer` one is clearer in parentheses even though
+/// they are not needed.
+fn clarifies(outer: &str, inner: &str) -> bool {
+    let is_comparison = |op| binary_precedence(op) == binary_precedence("==");
+    let is_arithmetic = |op| matches!(op, "+" | "-" | "*" | "/");
+    (outer == "||" && inner == "&&") || (is_comparison(outer) && is_arithmetic(inner))
+}
+
+fn remove(paren: &TokenData) -> Edit {
+    Edit {
+        offset: paren.offset,
+        len: paren.content.len(),
+        text: "",
+    }
+}
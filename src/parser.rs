@@ -34,7 +34,8 @@ peg::parser! {
         rule duration() -> TokenData<'a> = [Token::Duration(d)] {d}
         rule bytes() -> TokenData<'a> = [Token::Bytes(d)] {d}
         rule string() -> TokenData<'a> = [Token::String(d)] {d}
-        rule ident() -> TokenData<'a> = [Token::Ident(d)] {d}
+        rule placeholder() -> TokenData<'a> = [Token::Placeholder(d)] {d}
+        rule ident() -> TokenData<'a> = [Token::Ident(d)] {d} / placeholder()
         rule backendPropIdent() -> TokenData<'a> = [Token::BackendPropIdent(d)] {d}
         rule semicolon() -> TokenData<'a> = [Token::Semicolon(d)] {d}
         rule lBrace() -> TokenData<'a> = [Token::LBrace(d)] {d}
@@ -121,15 +122,13 @@ peg::parser! {
             }
 
         rule expression() -> Expression<'a> = precedence!{
-            x:(@) op:plus() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
-            x:(@) op:minus() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
-            --
-            x:(@) op:multiply() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
-            x:(@) op:divide() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
+            x:(@) op:or() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
             --
             x:(@) op:and() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
             --
-            x:(@) op:or() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
+            op:negate() x:@ {
+                Expression::Neg { op, expr: Box::new(x) }
+            }
             --
             x:(@) op:equals() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
             x:(@) op:notEquals() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
@@ -140,9 +139,11 @@ peg::parser! {
             x:(@) op:greaterEquals() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
             x:(@) op:lesserEquals() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
             --
-            op:negate() x:@ {
-                Expression::Neg { op, expr: Box::new(x) }
-            }
+            x:(@) op:plus() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
+            x:(@) op:minus() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
+            --
+            x:(@) op:multiply() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
+            x:(@) op:divide() y:@ {Expression::Binary { left: Box::new(x), op, right: Box::new(y) }}
             --
             l:literal() {l}
             e:ident_call_expr() {Expression::IdentCall(e)}
@@ -259,6 +260,9 @@ peg::parser! {
             }
             / i:include_decl() {Statement::Include(i)}
             / return_statement()
+            / token:placeholder() semi:semicolon()? {
+                Statement::Placeholder { token, semi }
+            }
 
         rule sub_decl() -> TopLevelDeclaration<'a>
             = sub:sub() name:ident() lbrace:lBrace() statements:statement()* rbrace:rBrace() {
@@ -273,89 +277,103 @@ peg::parser! {
             / backend_decl()
             / probe_decl()
             / sub_decl()
+            / token:placeholder() semi:semicolon()? {
+                TopLevelDeclaration::Placeholder { token, semi }
+            }
 
         pub(super) rule source_file() -> SourceFile<'a>
             = declarations:top_level_declaration()* eof:eof() {
                 SourceFile { declarations, eof }
             }
+
+        pub(super) rule statements_fragment() -> Fragment<'a>
+            = statements:statement()* eof:eof() {
+                Fragment::Statements { statements, eof }
+            }
+
+        pub(super) rule expression_fragment() -> Fragment<'a>
+            = expr:expression() eof:eof() {
+                Fragment::Expression { expr, eof }
+            }
     }
 }
 
-pub fn parse<'a>(tokens: &'a [Token<'a>]) -> Result<SourceFile, crate::error::E> {
-    let result = vcl::source_file(tokens);
-    match result {
-        Ok(f) => Ok(f),
-        Err(e) => {
-            let tok = tokens[e.location];
-            let (token, position) = match tok {
-                Token::Acl(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Vcl(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Import(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Include(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::From(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Probe(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Backend(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::None(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Sub(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Set(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Call(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Unset(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::If(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Else(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::ElseIf(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Return(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::New(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Bool(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Number(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Duration(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Bytes(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::String(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Ident(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::BackendPropIdent(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Semicolon(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::LBrace(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::RBrace(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::LParen(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::RParen(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Negate(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Assign(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Plus(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Minus(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Multiply(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Divide(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Comma(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Or(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::And(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Equals(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::NotEquals(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Matches(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Greater(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Lesser(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::GreaterEquals(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::LesserEquals(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Increment(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Decrement(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::ShiftLeft(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::ShiftRight(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::AddAssign(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::SubtractAssign(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::MultiplyAssign(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::DivideAssign(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::NotMatches(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Modulo(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::BitwiseAnd(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::BitwiseOr(d) => (d.content.to_string(), Some((d.line, d.column))),
-                Token::Eof(d) => (d.content.to_string(), Some((d.line, d.column))),
-                _ => ("".to_string(), None),
-            };
-            Err(match position {
-                Some((line, column)) => crate::error::E::ParsingFailed {
-                    token,
-                    line,
-                    column,
-                },
-                None => crate::error::E::ParsingTriviaFailed,
-            })
-        }
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum FragmentKind {
+    /// Try top-level declarations, then statements, then a single expression
+    Auto,
+    /// Top-level declarations, like a complete VCL file
+    TopLevel,
+    /// Bare statements, like a file included inside a sub body
+    Statements,
+    /// A single expression
+    Expression,
+}
+
+pub fn parse<'a>(tokens: &'a [Token<'a>]) -> Result<SourceFile<'a>, crate::error::E> {
+    vcl::source_file(tokens).map_err(|e| parse_error(tokens, e.location))
+}
+
+pub fn parse_fragment<'a>(
+    tokens: &'a [Token<'a>],
+    kind: FragmentKind,
+) -> Result<Fragment<'a>, crate::error::E> {
+    let result = match kind {
+        FragmentKind::Auto => vcl::source_file(tokens)
+            .map(Fragment::TopLevel)
+            .or_else(|e1| vcl::statements_fragment(tokens).map_err(|e2| furthest_location(e1, e2)))
+            .or_else(|e1| vcl::expression_fragment(tokens).map_err(|e2| furthest_location(e1, e2))),
+        FragmentKind::TopLevel => vcl::source_file(tokens).map(Fragment::TopLevel),
+        FragmentKind::Statements => vcl::statements_fragment(tokens),
+        FragmentKind::Expression => vcl::expression_fragment(tokens),
+    };
+    result.map_err(|e| parse_error(tokens, e.location))
+}
+
+/// How tightly a binary operator binds, higher binds tighter. Mirrors the precedence table of
+/// the `expression` rule, which follows Varnish; an operand whose operator binds looser needs
+/// parentheses.
+pub fn binary_precedence(op: &str) -> Option<u8> {
+    match op {
+        "||" => Some(1),
+        "&&" => Some(2),
+        "==" | "!=" | "~" | "!~" | ">" | "<" | ">=" | "<=" => Some(4),
+        "+" | "-" => Some(5),
+        "*" | "/" => Some(6),
+        _ => None,
+    }
+}
+
+/// How tightly `!` binds: looser than comparisons, so `!a == b` is `!(a == b)`.
+pub const NEGATION_PRECEDENCE: u8 = 3;
+
+/// How tightly `expr` binds as an operand, on the scale of [`binary_precedence`].
+pub fn precedence(expr: &Expression) -> u8 {
+    match expr {
+        Expression::Binary { op, .. } => binary_precedence(op.content).unwrap_or(0),
+        Expression::Neg { .. } => NEGATION_PRECEDENCE,
+        _ => u8::MAX,
+    }
+}
+
+fn furthest_location(
+    e1: peg::error::ParseError<usize>,
+    e2: peg::error::ParseError<usize>,
+) -> peg::error::ParseError<usize> {
+    if e2.location > e1.location {
+        e2
+    } else {
+        e1
+    }
+}
+
+fn parse_error(tokens: &[Token], location: usize) -> crate::error::E {
+    match tokens[location].data() {
+        Some(d) => crate::error::E::ParsingFailed {
+            token: d.content.to_string(),
+            line: d.line,
+            column: d.column,
+        },
+        None => crate::error::E::ParsingTriviaFailed,
     }
 }
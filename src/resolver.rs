@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    error::E,
+    lexer::{self, Delimiters},
+    parser::{self, FragmentKind},
+};
+
+/// All files reachable from an entrypoint, in the order they were first included.
+pub struct Project {
+    pub units: Vec<SourceUnit>,
+}
+
+pub struct SourceUnit {
+    pub path: PathBuf,
+    pub source: String,
+    pub includes: Vec<ResolvedInclude>,
+    canonical: PathBuf,
+}
+
+pub struct ResolvedInclude {
+    pub name: String,
+    pub line: usize,
+    pub path: PathBuf,
+}
+
+impl Project {
+    pub fn position(&self, path: &Path) -> Option<usize> {
+        let canonical = canonical_path(path);
+        self.units.iter().position(|u| u.canonical == canonical)
+    }
+}
+
+pub struct IncludeResolver {
+    search_paths: Vec<PathBuf>,
+    placeholders: Vec<Delimiters>,
+}
+
+impl IncludeResolver {
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
+        Self {
+            search_paths,
+            placeholders: vec![],
+        }
+    }
+
+    /// Lexes files with template placeholders, see [`lexer::lex_with_placeholders`].
+    pub fn with_placeholders(mut self, placeholders: Vec<Delimiters>) -> Self {
+        self.placeholders = placeholders;
+        self
+    }
+
+    /// Loads the entrypoint and every file it includes, transitively.
+    pub fn resolve(&self, entrypoint: &Path, source: String) -> Result<Project, E> {
+        let mut project = Project { units: vec![] };
+        let mut stack = vec![];
+        self.visit(entrypoint.to_path_buf(), source, &mut stack, &mut project)?;
+        Ok(project)
+    }
+
+    fn visit(
+        &self,
+        path: PathBuf,
+        source: String,
+        stack: &mut Vec<(PathBuf, PathBuf)>,
+        project: &mut Project,
+    ) -> Result<(), E> {
+        let canonical = canonical_path(&path);
+        let includes = self.find_includes(&path, &source).map_err(|e| E::InFile {
+            path: path.display().to_string(),
+            error: Box::new(e),
+        })?;
+
+        stack.push((path.clone(), canonical.clone()));
+        let unit_idx = project.units.len();
+        project.units.push(SourceUnit {
+            path,
+            source,
+            includes: vec![],
+            canonical,
+        });
+
+        for include in &includes {
+            let included_canonical = canonical_path(&include.path);
+            if let Some(pos) = stack.iter().position(|(_, c)| *c == included_canonical) {
+                let mut chain: Vec<String> = stack[pos..]
+                    .iter()
+                    .map(|(p, _)| p.display().to_string())
+                    .collect();
+                chain.push(include.path.display().to_string());
+                return Err(E::IncludeCycle(chain));
+            }
+            if project
+                .units
+                .iter()
+                .any(|u| u.canonical == included_canonical)
+            {
+                continue;
+            }
+
+            let source = read_source(&include.path).map_err(|e| E::InFile {
+                path: include.path.display().to_string(),
+                error: Box::new(e),
+            })?;
+            self.visit(include.path.clone(), source, stack, project)?;
+        }
+
+        project.units[unit_idx].includes = includes;
+        stack.pop();
+        Ok(())
+    }
+
+    fn find_includes(&self, path: &Path, source: &str) -> Result<Vec<ResolvedInclude>, E> {
+        let tokens = lexer::lex_with_placeholders(source, &self.placeholders)?;
+        let fragment = parser::parse_fragment(&tokens, FragmentKind::Auto)?;
+        fragment
+            .includes()
+            .into_iter()
+            .map(|i| {
+                let name = i.path();
+                match self.find(name, path) {
+                    Some(found) => Ok(ResolvedInclude {
+                        name: name.to_string(),
+                        line: i.include.line,
+                        path: found,
+                    }),
+                    None => Err(E::IncludeNotFound {
+                        name: name.to_string(),
+                        line: i.include.line,
+                        column: i.include.column,
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    /// Looks the include up relative to the including file first, then in the search paths.
+    fn find(&self, name: &str, including: &Path) -> Option<PathBuf> {
+        let relative_base = including.parent().unwrap_or(Path::new(""));
+        std::iter::once(relative_base)
+            .chain(self.search_paths.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|candidate| candidate.is_file())
+    }
+}
+
+fn read_source(path: &Path) -> Result<String, E> {
+    let data = std::fs::read(path).map_err(E::FailedToReadInput)?;
+    Ok(std::str::from_utf8(&data)?.to_string())
+}
+
+fn canonical_path(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
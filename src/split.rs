@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::{IncludeData, SourceFile, TopLevelDeclaration},
+    ast_emitter::{directives, kept_as_written, AstEmitter},
+    error::E,
+    lexer::{self, lex_trivia, TokenData, TriviaToken},
+    parser,
+    style::Style,
+};
+
+pub struct SplitFile {
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+/// Moves every sub into its own file and backends, probes and ACLs into one file per kind.
+/// The first returned file is the main file, which includes all the others in place of the
+/// declarations that were moved out of it. The source is rewritten as `style` asks for first.
+pub fn split(
+    source: &str,
+    main_name: &str,
+    include_prefix: &str,
+    style: &Style,
+) -> Result<Vec<SplitFile>, E> {
+    let source = &*crate::rewrite(source, style, None, &[])?;
+    let tokens = lexer::lex(source)?;
+    let mut sf = parser::parse(&tokens)?;
+
+    // Declarations kept as written by `vclfmt` directives are moved as text, since the code
+    // around them in the source does not follow them into the split files. Those kept by a
+    // directive before another declaration get a `skip` directive of their own.
+    let kept = kept_as_written(&sf.declarations, TopLevelDeclaration::first_token)?;
+    let mut skip_trivia = vec![];
+    for td in &sf.declarations {
+        let first = td.first_token();
+        skip_trivia.push(if directives(first)?.is_empty() {
+            format!("{}# vclfmt: skip\n", first.pre_trivia)
+        } else {
+            first.pre_trivia.to_string()
+        });
+    }
+    let mut texts = vec![];
+    for (idx, td) in sf.declarations.iter().enumerate() {
+        let first = td.first_token();
+        let next = sf
+            .declarations
+            .get(idx + 1)
+            .map_or(&sf.eof, TopLevelDeclaration::first_token);
+        let end = next.offset - next.pre_trivia.len();
+        texts.push(TokenData {
+            content: &source[first.offset..end],
+            pre_trivia: &skip_trivia[idx],
+            ..*first
+        });
+    }
+
+    let mut file_names: Vec<String> = vec![main_name.to_string()];
+    let mut kind_files: HashMap<&str, usize> = HashMap::new();
+    let mut assignments = vec![];
+    for td in &sf.declarations {
+        let assignment = match td {
+            TopLevelDeclaration::Sub { name, .. } => Some(new_file(&mut file_names, name.content)),
+            TopLevelDeclaration::Backend(_) => {
+                Some(kind_file(&mut file_names, &mut kind_files, "backends"))
+            }
+            TopLevelDeclaration::Probe { .. } => {
+                Some(kind_file(&mut file_names, &mut kind_files, "probes"))
+            }
+            TopLevelDeclaration::Acl { .. } => {
+                Some(kind_file(&mut file_names, &mut kind_files, "acls"))
+            }
+            _ => None,
+        };
+        assignments.push(assignment);
+    }
+    for ((td, text), kept) in sf.declarations.iter_mut().zip(texts).zip(kept) {
+        if kept {
+            *td = TopLevelDeclaration::Placeholder {
+                token: text,
+                semi: None,
+            };
+        }
+    }
+
+    let include_names: Vec<String> = file_names
+        .iter()
+        .map(|n| format!("\"{include_prefix}{n}\""))
+        .collect();
+    let mut main_declarations = vec![];
+    let mut after_include = false;
+    let mut split_declarations: Vec<Vec<TopLevelDeclaration>> =
+        file_names.iter().map(|_| vec![]).collect();
+    for (td, assignment) in sf.declarations.into_iter().zip(assignments) {
+        match assignment {
+            Some(idx) => {
+                if split_declarations[idx].is_empty() {
+                    let pre_trivia = if !after_include && has_blank_line(td.first_token())? {
+                        "\n\n"
+                    } else {
+                        "\n"
+                    };
+                    main_declarations.push(TopLevelDeclaration::Include(IncludeData {
+                        include: TokenData::synthetic("include", pre_trivia),
+                        name: TokenData::synthetic(&include_names[idx], ""),
+                        semi: TokenData::synthetic(";", ""),
+                    }));
+                    after_include = true;
+                }
+                split_declarations[idx].push(td);
+            }
+            None => {
+                main_declarations.push(td);
+                after_include = false;
+            }
+        }
+    }
+    split_declarations[0] = main_declarations;
+
+    let mut result = vec![];
+    for (idx, (name, declarations)) in file_names.into_iter().zip(split_declarations).enumerate() {
+        let eof = if idx == 0 {
+            sf.eof
+        } else {
+            TokenData::synthetic("", "")
+        };
+        let mut contents = vec![];
+        AstEmitter::new(&mut contents, style, 0).emit(&SourceFile { declarations, eof })?;
+        result.push(SplitFile { name, contents });
+    }
+    Ok(result)
+}
+
+fn new_file(file_names: &mut Vec<String>, stem: &str) -> usize {
+    let mut name = format!("{stem}.vcl");
+    let mut counter = 1;
+    while file_names.contains(&name) {
+        counter += 1;
+        name = format!("{stem}-{counter}.vcl");
+    }
+    file_names.push(name);
+    file_names.len() - 1
+}
+
+fn kind_file<'k>(
+    file_names: &mut Vec<String>,
+    kind_files: &mut HashMap<&'k str, usize>,
+    kind: &'k str,
+) -> usize {
+    *kind_files
+        .entry(kind)
+        .or_insert_with(|| new_file(file_names, kind))
+}
+
+fn has_blank_line(token: &TokenData) -> Result<bool, E> {
+    let newlines = lex_trivia(token.pre_trivia)?
+        .iter()
+        .take_while(|t| matches!(t, TriviaToken::Newline))
+        .count();
+    Ok(newlines > 1)
+}
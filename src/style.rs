@@ -0,0 +1,184 @@
+/// Formatting options, shared by the command line and library callers.
+#[derive(Clone, Debug, PartialEq, clap::Args)]
+#[command(next_help_heading = "Style")]
+pub struct Style {
+    /// Number of spaces to use for indentation
+    #[arg(short, long, default_value_t = 4)]
+    pub indent: usize,
+
+    /// Put a space between `return` and `(`
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub space_after_return: bool,
+
+    /// Put a space between `if` and `(`
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub space_after_if: bool,
+
+    /// Put a space between `else if` and `(`
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub space_after_elseif: bool,
+
+    /// Put a space between a function name and `(`
+    #[arg(long, value_name = "BOOL", default_value_t = false, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub space_before_call_paren: bool,
+
+    /// Put spaces around `=` of named arguments
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub spaces_around_named_args: bool,
+
+    /// Put a space after `!`
+    #[arg(long, value_name = "BOOL", default_value_t = false, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub space_after_not: bool,
+
+    /// Put spaces around `/` of ACL masks
+    #[arg(long, value_name = "BOOL", default_value_t = false, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub spaces_around_acl_mask: bool,
+
+    /// Brace placement of subs
+    #[arg(long, value_enum, value_name = "STYLE", default_value_t = BraceStyle::SameLine)]
+    pub sub_braces: BraceStyle,
+
+    /// Brace placement of backends, probes, ACLs
+    #[arg(long, value_enum, value_name = "STYLE", default_value_t = BraceStyle::SameLine)]
+    pub declaration_braces: BraceStyle,
+
+    /// Brace placement of `if` and `else` bodies
+    #[arg(long, value_enum, value_name = "STYLE", default_value_t = BraceStyle::SameLine)]
+    pub if_braces: BraceStyle,
+
+    /// Put `else` on the same line as the preceding `}`
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub cuddle_else: bool,
+
+    /// Maximum number of consecutive blank lines to keep
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub max_blank_lines: usize,
+
+    /// Minimum number of blank lines between top-level declarations, except between
+    /// consecutive imports and consecutive includes
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub blank_lines_between_declarations: usize,
+
+    /// Remove blank lines at the start and end of blocks
+    #[arg(long, value_name = "BOOL", default_value_t = false, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub trim_blank_lines_in_blocks: bool,
+
+    /// Separate `if` statements from surrounding statements with a blank line
+    #[arg(long, value_name = "BOOL", default_value_t = false, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub blank_lines_around_if: bool,
+
+    /// Align `=` of consecutive backend and probe properties and `set` statements
+    #[arg(long, value_name = "BOOL", default_value_t = false, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub align_assignments: bool,
+
+    /// Reorder top-level declarations: vcl version, imports (sorted), includes, probes,
+    /// backends, ACLs, subs
+    #[arg(long, value_name = "BOOL", default_value_t = false, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub organize: bool,
+
+    /// Check ACL entries and print IPv6 addresses in canonical form
+    #[arg(long, value_name = "BOOL", default_value_t = false, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub normalize_acls: bool,
+
+    /// Sort ACL entries by network, implies --normalize-acls
+    #[arg(long, value_name = "BOOL", default_value_t = false, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub sort_acls: bool,
+
+    /// Remove parentheses that are not needed, and add them where `&&` and `||`, or comparisons
+    /// and arithmetic, are mixed
+    #[arg(long, value_name = "BOOL", default_value_t = false, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub normalize_parentheses: bool,
+
+    /// Line width beyond which chains of `+` with strings in them are broken into one operand
+    /// per line
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub max_width: usize,
+
+    /// Where long chains of `+` are broken
+    #[arg(long, value_enum, value_name = "POSITION", default_value_t = BreakPosition::BeforeOperator)]
+    pub concatenation_break: BreakPosition,
+
+    /// Line endings of the output
+    #[arg(long, value_enum, value_name = "STYLE", default_value_t = NewlineStyle::Auto)]
+    pub newline_style: NewlineStyle,
+
+    /// End the output with a line break; with false, line breaks at the end are removed
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    #[arg(hide_possible_values = true)]
+    pub final_newline: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum NewlineStyle {
+    /// The line ending used most in the input
+    Auto,
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    Crlf,
+    /// `\r\n` on Windows, `\n` elsewhere
+    Native,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum BreakPosition {
+    /// Start continuation lines with `+`
+    BeforeOperator,
+    /// End broken lines with `+`
+    AfterOperator,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum BraceStyle {
+    /// At the end of the line (K&R)
+    SameLine,
+    /// On a line of its own (Allman)
+    NextLine,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            indent: 4,
+            space_after_return: true,
+            space_after_if: true,
+            space_after_elseif: true,
+            space_before_call_paren: false,
+            spaces_around_named_args: true,
+            space_after_not: false,
+            spaces_around_acl_mask: false,
+            sub_braces: BraceStyle::SameLine,
+            declaration_braces: BraceStyle::SameLine,
+            if_braces: BraceStyle::SameLine,
+            cuddle_else: true,
+            max_blank_lines: 1,
+            blank_lines_between_declarations: 0,
+            trim_blank_lines_in_blocks: false,
+            blank_lines_around_if: false,
+            align_assignments: false,
+            organize: false,
+            normalize_acls: false,
+            sort_acls: false,
+            normalize_parentheses: false,
+            max_width: 100,
+            concatenation_break: BreakPosition::BeforeOperator,
+            newline_style: NewlineStyle::Auto,
+            final_newline: true,
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{
+    error::{E, R},
+    lexer::{self, Delimiters, Token, TokenData},
+    parser::{self, FragmentKind},
+};
+
+/// Checks that `output` contains the same code as `input`, only laid out differently.
+///
+/// Both are lexed and parsed again; tokens are compared one by one, ignoring whitespace
+/// and comments, and the resulting syntax trees are compared as well.
+pub fn verify(
+    input: &str,
+    output: &str,
+    fragment: Option<FragmentKind>,
+    placeholders: &[Delimiters],
+) -> R {
+    let expected = lexer::lex_with_placeholders(input, placeholders)?;
+    let actual = lexer::lex_with_placeholders(output, placeholders)
+        .map_err(|e| E::InvalidOutput(Box::new(e)))?;
+
+    let mut expected_tokens = code_tokens(&expected).into_iter();
+    let mut actual_tokens = code_tokens(&actual).into_iter();
+    loop {
+        match (expected_tokens.next(), actual_tokens.next()) {
+            (None, None) => break,
+            (Some(e), Some(a)) if e.1 == a.1 => {}
+            (Some(e), Some(a)) => {
+                return Err(E::TokenMismatch {
+                    expected: e.1.to_string(),
+                    expected_line: e.0.line,
+                    expected_column: e.0.column,
+                    found: a.1.to_string(),
+                    line: a.0.line,
+                    column: a.0.column,
+                })
+            }
+            (Some(e), None) => {
+                let end = actual.last().and_then(Token::data);
+                return Err(E::MissingToken {
+                    expected: e.1.to_string(),
+                    expected_line: e.0.line,
+                    expected_column: e.0.column,
+                    line: end.map_or(1, |d| d.line),
+                    column: end.map_or(1, |d| d.column),
+                });
+            }
+            (None, Some(a)) => {
+                return Err(E::ExtraToken {
+                    found: a.1.to_string(),
+                    line: a.0.line,
+                    column: a.0.column,
+                })
+            }
+        }
+    }
+
+    verify_tree(&expected, &actual, fragment)
+}
+
+/// Checks that `actual` parses into the same syntax tree as `expected`.
+fn verify_tree(expected: &[Token], actual: &[Token], fragment: Option<FragmentKind>) -> R {
+    let actual_tree = syntax_tree(actual, fragment).map_err(|e| E::InvalidOutput(Box::new(e)))?;
+    if syntax_tree(expected, fragment)? != actual_tree {
+        return Err(E::SyntaxTreeMismatch);
+    }
+
+    Ok(())
+}
+
+/// Checks that formatting `first` again produced `second` without any changes.
+pub fn verify_idempotent(first: &str, second: &str) -> R {
+    if first == second {
+        return Ok(());
+    }
+
+    let mut line = 1;
+    let mut column = 1;
+    for (a, b) in first.chars().zip(second.chars()) {
+        if a != b {
+            break;
+        }
+        if a == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    Err(E::NotIdempotent { line, column })
+}
+
+/// Tokens with their contents, spelling `elseif` as `else if` like the formatter does. The end
+/// of the file is left out.
+fn code_tokens<'a>(tokens: &'a [Token<'a>]) -> Vec<(&'a TokenData<'a>, &'a str)> {
+    let mut result = vec![];
+    for t in tokens {
+        match t {
+            Token::ElseIf(d) => result.extend([(d, "else"), (d, "if")]),
+            Token::Eof(_) => {}
+            t => result.extend(t.data().map(|d| (d, d.content))),
+        }
+    }
+    result
+}
+
+fn syntax_tree<'a>(tokens: &'a [Token<'a>], fragment: Option<FragmentKind>) -> Result<String, E> {
+    let tree = match fragment {
+        Some(kind) => parser::parse_fragment(tokens, kind).map(|f| format!("{f:?}")),
+        None => parser::parse(tokens).map(|sf| format!("{sf:?}")),
+    }?;
+    Ok(tree.replace(r#"elseif: ["elseif"]"#, r#"elseif: ["else", "if"]"#))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "sub vcl_recv {\n    set req.http.a = \"1\";\n}\n";
+
+    #[test]
+    fn changed_token_is_reported() {
+        let output = "sub vcl_recv {\n    set req.http.b = \"1\";\n}\n";
+        match verify(INPUT, output, None, &[]) {
+            Err(E::TokenMismatch {
+                expected,
+                expected_line,
+                expected_column,
+                found,
+                line,
+                column,
+            }) => {
+                assert_eq!(expected, "req.http.a");
+                assert_eq!((expected_line, expected_column), (2, 9));
+                assert_eq!(found, "req.http.b");
+                assert_eq!((line, column), (2, 9));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn missing_token_is_reported() {
+        let output = "sub vcl_recv {\n    set req.http.a = \"1\";\n";
+        match verify(INPUT, output, None, &[]) {
+            Err(E::MissingToken {
+                expected,
+                expected_line,
+                expected_column,
+                line,
+                column,
+            }) => {
+                assert_eq!(expected, "}");
+                assert_eq!((expected_line, expected_column), (3, 1));
+                assert_eq!((line, column), (3, 1));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn extra_token_is_reported() {
+        let output = "sub vcl_recv {\n    set req.http.a = \"1\";\n}\n}\n";
+        match verify(INPUT, output, None, &[]) {
+            Err(E::ExtraToken {
+                found,
+                line,
+                column,
+            }) => {
+                assert_eq!(found, "}");
+                assert_eq!((line, column), (4, 1));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn changed_tree_is_reported() -> R {
+        // Equal tokens always parse the same, so `verify` reports changed grouping as a changed
+        // token first; the tree comparison is checked on its own.
+        let expected = lexer::lex("sub vcl_recv {\n    if (!a == b) {}\n}\n")?;
+        let actual = lexer::lex("sub vcl_recv {\n    if ((!a) == b) {}\n}\n")?;
+        assert!(matches!(
+            verify_tree(&expected, &actual, None),
+            Err(E::SyntaxTreeMismatch)
+        ));
+        verify_tree(&expected, &expected, None)
+    }
+
+    #[test]
+    fn non_idempotent_output_is_reported() {
+        let first = "sub vcl_recv {\n    set req.http.a = \"1\";\n}\n";
+        let second = "sub vcl_recv {\n    set req.http.a  = \"1\";\n}\n";
+        match verify_idempotent(first, second) {
+            Err(E::NotIdempotent { line, column }) => assert_eq!((line, column), (2, 20)),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(verify_idempotent(first, first).is_ok());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::error::E;
+
+/// VCL passed to a `-vcl` or `-vcl+backend` argument in a varnishtest script.
+pub struct VclBlock {
+    /// Byte range of the block contents, without the enclosing braces.
+    pub start: usize,
+    pub end: usize,
+    /// Line of the opening brace.
+    pub line: usize,
+    /// Whitespace at the start of the line with the opening brace.
+    pub line_indent: String,
+}
+
+/// Finds VCL blocks in the top-level commands of a varnishtest script.
+///
+/// The script is split into words like varnishtest does: `#` starts a comment, `"` a string
+/// and `{` a block that ends at the matching `}`, counting nested braces and nothing else.
+pub fn vcl_blocks(source: &str) -> Result<Vec<VclBlock>, E> {
+    let bytes = source.as_bytes();
+    let mut blocks = vec![];
+    let mut last_word = "";
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'\n' | b' ' | b'\t' | b'\r' => idx += 1,
+            b'#' => idx = source[idx..].find('\n').map_or(source.len(), |i| idx + i),
+            b'"' => {
+                let mut end = idx + 1;
+                while end < bytes.len() && bytes[end] != b'"' {
+                    end += if bytes[end] == b'\\' { 2 } else { 1 };
+                }
+                last_word = "";
+                idx = end + 1;
+            }
+            b'{' => {
+                let end = matching_brace(bytes, idx).ok_or(E::UnterminatedBlock {
+                    line: line_of(source, idx),
+                })?;
+                if last_word == "-vcl" || last_word == "-vcl+backend" {
+                    let line_start = source[..idx].rfind('\n').map_or(0, |i| i + 1);
+                    let line_indent = source[line_start..]
+                        .chars()
+                        .take_while(|c| *c == ' ' || *c == '\t')
+                        .collect();
+                    blocks.push(VclBlock {
+                        start: idx + 1,
+                        end,
+                        line: line_of(source, idx),
+                        line_indent,
+                    });
+                }
+                last_word = "";
+                idx = end + 1;
+            }
+            _ => {
+                let len = source[idx..]
+                    .find(|c: char| c.is_ascii_whitespace() || c == '{' || c == '"')
+                    .unwrap_or(source.len() - idx);
+                last_word = &source[idx..idx + len];
+                idx += len;
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+/// Replaces the contents of every VCL block with the result of `format_block`, which is given
+/// the contents and the indentation level of the block. Everything else is kept as is.
+pub fn format(
+    source: &str,
+    indent: usize,
+    mut format_block: impl FnMut(&str, usize) -> Result<Vec<u8>, E>,
+) -> Result<Vec<u8>, E> {
+    let mut out = vec![];
+    let mut copied = 0;
+    for block in vcl_blocks(source)? {
+        let contents = &source[block.start..block.end];
+        if contents.trim().is_empty() {
+            continue;
+        }
+        let base_indent = indent_level(&block.line_indent, indent) + 1;
+        let formatted = format_block(contents, base_indent).map_err(|e| E::InBlock {
+            line: block.line,
+            error: Box::new(e),
+        })?;
+        out.extend_from_slice(&source.as_bytes()[copied..block.start]);
+        out.push(b'\n');
+        out.extend_from_slice(&formatted);
+        out.extend_from_slice(block.line_indent.as_bytes());
+        copied = block.end;
+    }
+    out.extend_from_slice(&source.as_bytes()[copied..]);
+    Ok(out)
+}
+
+fn matching_brace(bytes: &[u8], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (idx, b) in bytes.iter().enumerate().skip(open) {
+        match b {
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Number of indentation levels of `whitespace`, counting a tab as one level.
+fn indent_level(whitespace: &str, indent: usize) -> usize {
+    let tabs = whitespace.matches('\t').count();
+    let spaces = whitespace.matches(' ').count();
+    tabs + spaces / indent.max(1)
+}
+
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
@@ -0,0 +1,137 @@
+use vcl_formatter::{builder::Builder, lexer, parser};
+
+#[test]
+fn built_file_is_formatted() {
+    let b = Builder::new();
+    let backends = ["app1", "app2"];
+    let mut declarations = vec![b.vcl_version("4.1"), b.import("directors")];
+    declarations.push(b.probe(
+        "healthy",
+        vec![
+            b.property("url", b.string("/health")),
+            b.property("interval", b.literal("5s")),
+            b.property_strings("request", &["GET / HTTP/1.1", "Connection: close"]),
+        ],
+    ));
+    for name in backends {
+        declarations.push(b.backend(
+            name,
+            vec![
+                b.property("host", b.string(&format!("{name}.internal"))),
+                b.property("port", b.string("8080")),
+                b.property_block("probe", vec![b.property("url", b.string("/"))]),
+            ],
+        ));
+    }
+    declarations.push(b.acl(
+        "purgers",
+        vec![
+            b.acl_entry("localhost", None),
+            b.acl_entry("10.0.0.0", Some(8)),
+        ],
+    ));
+    declarations.push(
+        b.sub(
+            "vcl_init",
+            std::iter::once(b.new_object("pool", "directors.round_robin", vec![]))
+                .chain(
+                    backends
+                        .iter()
+                        .map(|name| b.invoke("pool.add_backend", vec![b.ident(name)])),
+                )
+                .collect(),
+        ),
+    );
+    declarations.push(b.sub(
+        "vcl_recv",
+        vec![
+            b.if_(
+                b.and(
+                    b.binary(b.ident("req.method"), "==", b.string("PURGE")),
+                    b.negate(b.binary(b.ident("client.ip"), "~", b.ident("purgers"))),
+                ),
+                vec![b.return_with("synth", Some(vec![b.number(405)]))],
+            )
+            .else_if(
+                b.binary(
+                    b.binary(b.ident("a"), "+", b.ident("b")),
+                    "*",
+                    b.ident("c"),
+                ),
+                vec![b.unset("req.http.Cookie")],
+            )
+            .else_(vec![
+                b.set("req.backend_hint", b.call("pool.backend", vec![])),
+                b.set(
+                    "req.http.X-Note",
+                    b.string("say \"hi\"} and \"}\" too"),
+                ),
+                b.call_sub("normalize"),
+            ])
+            .build(),
+            b.return_("hash"),
+        ],
+    ));
+    let file = b.file(declarations);
+
+    let printed = b.print(&file, 4).unwrap();
+    assert_eq!(
+        printed,
+        r#"vcl 4.1;
+
+import directors;
+
+probe healthy {
+    .url = "/health";
+    .interval = 5s;
+    .request =
+        "GET / HTTP/1.1"
+        "Connection: close";
+}
+
+backend app1 {
+    .host = "app1.internal";
+    .port = "8080";
+    .probe = {
+        .url = "/";
+    }
+}
+
+backend app2 {
+    .host = "app2.internal";
+    .port = "8080";
+    .probe = {
+        .url = "/";
+    }
+}
+
+acl purgers {
+    "localhost";
+    "10.0.0.0"/8;
+}
+
+sub vcl_init {
+    new pool = directors.round_robin();
+    pool.add_backend(app1);
+    pool.add_backend(app2);
+}
+
+sub vcl_recv {
+    if (req.method == "PURGE" && !(client.ip ~ purgers)) {
+        return (synth(405));
+    } else if ((a + b) * c) {
+        unset req.http.Cookie;
+    } else {
+        set req.backend_hint = pool.backend();
+        set req.http.X-Note = {"say "hi""} + {"} and ""} + {"}" too"};
+        call normalize;
+    }
+    return (hash);
+}
+"#
+    );
+
+    let tokens = lexer::lex(&printed).unwrap();
+    let parsed = parser::parse(&tokens).unwrap();
+    assert_eq!(format!("{parsed:?}"), format!("{file:?}"));
+}
@@ -1,13 +1,16 @@
 use std::{
+    ffi::OsStr,
     io::{BufWriter, Write},
+    path::{Path, PathBuf},
     process::{Command, Output, Stdio},
 };
 
-fn process_vcl(data: &[u8], indent: &str) -> Output {
+fn process_vcl_with_args(data: &[u8], args: &[&str]) -> Output {
     let path = env!("CARGO_BIN_EXE_vcl-formatter");
     let child = Command::new(path)
-        .arg("-i")
-        .arg(indent)
+        .arg("--verify")
+        .arg("--verify-idempotent")
+        .args(args)
         .arg("-")
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -24,49 +27,358 @@ fn process_vcl(data: &[u8], indent: &str) -> Output {
     child.wait_with_output().unwrap()
 }
 
-fn verify_formatting(input: &[u8], expected: &[u8], indent: &str) {
-    let output = process_vcl(input, indent);
-    assert_eq!(&output.stdout[..], expected);
+fn run(args: &[&str]) -> Output {
+    let path = env!("CARGO_BIN_EXE_vcl-formatter");
+    Command::new(path)
+        .args(args)
+        .output()
+        .expect("Failed to execute command")
+}
+
+/// Set this environment variable to overwrite expected outputs of golden tests with actual ones.
+const BLESS_VAR: &str = "VCLFMT_BLESS";
+
+/// Turns `options.toml` into command line arguments: `key = value` becomes `--key value`,
+/// `key = true` becomes `--key` and arrays repeat the option for every element.
+fn options_to_args(path: &Path) -> Vec<String> {
+    let Ok(text) = std::fs::read_to_string(path) else {
+        return vec![];
+    };
+    let table: toml::Table = text
+        .parse()
+        .unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+    let mut args = vec![];
+    for (key, value) in table {
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => args.push(format!("--{key}")),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(s) => args.extend([format!("--{key}"), s]),
+                other => args.extend([format!("--{key}"), other.to_string()]),
+            }
+        }
+    }
+    args
+}
+
+/// Finds directories with an `input.*` file, e.g. `input.vcl` or `input.vtc`.
+fn golden_cases(dir: &Path, found: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            golden_cases(&path, found);
+        } else if path.file_stem().is_some_and(|s| s == "input") {
+            found.push(path);
+        }
+    }
+}
+
+/// Extension of the expected output: that of the input, or of what `--emit` prints.
+fn expected_extension<'a>(input_path: &'a Path, args: &[&str]) -> &'a OsStr {
+    let emit = args
+        .iter()
+        .position(|a| *a == "--emit")
+        .and_then(|idx| args.get(idx + 1));
+    match emit {
+        Some(&"ast-json") => OsStr::new("json"),
+        Some(&"tokens") => OsStr::new("txt"),
+        _ => input_path.extension().unwrap(),
+    }
+}
+
+/// Compares actual output with the expected file, or rewrites the expected file when blessing.
+/// A missing expected file means no output is expected.
+fn check_golden(path: &Path, actual: &[u8], bless: bool) -> Option<String> {
+    let expected = std::fs::read(path).unwrap_or_default();
+    if expected == actual {
+        return None;
+    }
+    if bless {
+        if actual.is_empty() {
+            std::fs::remove_file(path).unwrap();
+        } else {
+            std::fs::write(path, actual).unwrap();
+        }
+        return None;
+    }
+    Some(format!(
+        "{}:\n--- expected\n{}\n--- actual\n{}",
+        path.display(),
+        String::from_utf8_lossy(&expected),
+        String::from_utf8_lossy(actual)
+    ))
 }
 
-fn verify_error(input: &[u8], expected: &[u8], indent: &str) {
-    let output = process_vcl(input, indent);
-    assert_eq!(&output.stderr[..], expected);
+#[test]
+fn golden_files() {
+    let bless = std::env::var_os(BLESS_VAR).is_some();
+    let mut cases = vec![];
+    golden_cases(Path::new("tests/files"), &mut cases);
+    cases.sort();
+    assert!(!cases.is_empty());
+
+    let mut failures = vec![];
+    for input_path in cases {
+        let case = input_path.parent().unwrap();
+        let input = std::fs::read(&input_path).unwrap();
+        let args = options_to_args(&case.join("options.toml"));
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let output = process_vcl_with_args(&input, &args);
+        let expected = case
+            .join("expected")
+            .with_extension(expected_extension(&input_path, &args));
+        failures.extend(check_golden(&expected, &output.stdout, bless));
+        failures.extend(check_golden(
+            &case.join("expected.stderr"),
+            &output.stderr,
+            bless,
+        ));
+        // Cases are expected to succeed unless they give the exit code they fail with.
+        let status = match output.status.code() {
+            Some(0) => String::new(),
+            Some(code) => format!("{code}\n"),
+            None => "killed by a signal\n".to_string(),
+        };
+        failures.extend(check_golden(
+            &case.join("expected.status"),
+            status.as_bytes(),
+            bless,
+        ));
+    }
+    assert!(
+        failures.is_empty(),
+        "{}\n\nRun with {BLESS_VAR}=1 to update expected files.",
+        failures.join("\n\n")
+    );
 }
 
-fn verify_unchanged(data: &[u8], indent: &str) {
-    verify_formatting(data, data, indent)
+#[test]
+fn includes_are_followed() {
+    let output = run(&[
+        "--list-includes",
+        "-I",
+        "tests/files/includes/lib",
+        "tests/files/includes/main.vcl",
+    ]);
+    assert_eq!(
+        std::str::from_utf8(&output.stdout).unwrap(),
+        "tests/files/includes/main.vcl
+    line 3: \"backends.vcl\" -> tests/files/includes/backends.vcl
+    line 6: \"sub/recv.vcl\" -> tests/files/includes/sub/recv.vcl
+tests/files/includes/backends.vcl
+tests/files/includes/sub/recv.vcl
+    line 2: \"shared.vcl\" -> tests/files/includes/lib/shared.vcl
+tests/files/includes/lib/shared.vcl
+"
+    );
+
+    let output = run(&[
+        "--follow-includes",
+        "--check",
+        "-I",
+        "tests/files/includes/lib",
+        "tests/files/includes/main.vcl",
+    ]);
+    assert!(output.status.success());
 }
 
-const EXAMPLE_GOOD: &[u8] = include_bytes!("files/example/good.vcl");
-const NESTED_EXPR_GOOD: &[u8] = include_bytes!("files/nested_expr/good.vcl");
-const CORRUPTED_UNKNOWN_TOKEN: &[u8] = include_bytes!("files/corrupted/unknown_token.vcl");
-const CORRUPTED_UNEXPECTED_TOKEN: &[u8] = include_bytes!("files/corrupted/unexpected_token.vcl");
+#[test]
+fn includes_are_only_followed_from_vcl() {
+    for file in [
+        "tests/files/vtc/basic/input.vtc",
+        "tests/files/markdown/runbook/input.md",
+    ] {
+        let output = run(&["--list-includes", file]);
+        assert_eq!(output.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--input-format vcl"));
+    }
+}
 
 #[test]
-fn example_stays_unchanged() {
-    verify_unchanged(EXAMPLE_GOOD, "4")
+fn missing_include_is_reported() {
+    let output = run(&["--list-includes", "tests/files/includes/main.vcl"]);
+    assert_eq!(
+        &output.stderr[..],
+        b"Error: tests/files/includes/sub/recv.vcl: Included file \"shared.vcl\" not found (line=2, column=1)\n"
+    );
 }
 
 #[test]
-fn nested_expr_stays_unchanged() {
-    verify_unchanged(NESTED_EXPR_GOOD, "4")
+fn include_cycle_is_reported() {
+    let output = run(&["--list-includes", "tests/files/include_cycle/a.vcl"]);
+    assert_eq!(
+        &output.stderr[..],
+        b"Error: Include cycle: tests/files/include_cycle/a.vcl -> tests/files/include_cycle/b.vcl -> tests/files/include_cycle/a.vcl\n"
+    );
 }
 
 #[test]
-fn correct_unknown_token_error() {
-    verify_error(
-        CORRUPTED_UNKNOWN_TOKEN,
-        b"Error: Unknown token (line=3, column=1)\n",
-        "4",
+fn acl_warnings_name_the_file() {
+    let output = run(&[
+        "--follow-includes",
+        "--check",
+        "--normalize-acls",
+        "true",
+        "tests/files/acl/follow_includes/main.vcl",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::str::from_utf8(&output.stderr).unwrap(),
+        "Warning: tests/files/acl/follow_includes/acl.vcl: ACL purgers entry \"10.1.2.3\" is already covered by \"10.0.0.0\"/8 (line=3, covered by line 2)\n"
     );
 }
 
 #[test]
-fn correct_unexpected_token_error() {
-    verify_error(
-        CORRUPTED_UNEXPECTED_TOKEN,
-        b"Error: Unexpected token \"none\" (line=3, column=1)\n",
-        "4",
+fn includes_are_bundled() {
+    let output = run(&[
+        "bundle",
+        "-I",
+        "tests/files/includes/lib",
+        "tests/files/includes/main.vcl",
+    ]);
+    assert_eq!(
+        &output.stdout[..],
+        include_bytes!("files/includes/bundled.vcl")
     );
+
+    let output = run(&[
+        "bundle",
+        "--markers",
+        "-I",
+        "tests/files/includes/lib",
+        "tests/files/includes/main.vcl",
+    ]);
+    assert_eq!(
+        &output.stdout[..],
+        include_bytes!("files/includes/bundled_with_markers.vcl")
+    );
+
+    // Code kept as written by directives must not keep its includes either.
+    let output = run(&["bundle", "tests/files/includes/directives/main.vcl"]);
+    assert!(output.status.success());
+    assert_eq!(
+        &output.stdout[..],
+        include_bytes!("files/includes/directives/bundled.vcl")
+    );
+
+    let output = run(&[
+        "bundle",
+        "--organize",
+        "true",
+        "--sort-acls",
+        "true",
+        "--normalize-parentheses",
+        "true",
+        "tests/files/includes/rewrite/main.vcl",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        &output.stdout[..],
+        include_bytes!("files/includes/rewrite/bundled.vcl")
+    );
+}
+
+/// Splits `tests/files/<case>/main.vcl` with `options` and compares the files with those in its
+/// `expected` directory.
+fn check_split(case: &str, options: &[&str]) {
+    let input = format!("tests/files/{case}/main.vcl");
+    let input = input.as_str();
+    let out_dir = std::env::temp_dir().join(format!("vcl-formatter-{case}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&out_dir);
+    let out = out_dir.to_str().unwrap();
+    let output = run(&[&["split", "-o", out, input], options].concat());
+    assert!(output.status.success());
+
+    let mut names: Vec<_> = std::fs::read_dir(&out_dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    names.sort();
+    let expected_dir = Path::new("tests/files").join(case).join("expected");
+    let mut expected_names: Vec<_> = std::fs::read_dir(&expected_dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    expected_names.sort();
+    assert_eq!(names, expected_names);
+    for name in names {
+        assert_eq!(
+            std::fs::read(out_dir.join(&name)).unwrap(),
+            std::fs::read(expected_dir.join(&name)).unwrap(),
+            "{name:?} differs"
+        );
+    }
+
+    let output = run(&["split", "-o", out, input]);
+    assert!(!output.status.success());
+    std::fs::remove_dir_all(&out_dir).unwrap();
+}
+
+#[test]
+fn file_is_split() {
+    check_split("split", &[]);
+}
+
+#[test]
+fn split_keeps_code_excluded_by_directives() {
+    check_split("split_directives", &[]);
+}
+
+#[test]
+fn split_applies_rewriting_options() {
+    check_split(
+        "split_rewrite",
+        &[
+            "--organize",
+            "true",
+            "--sort-acls",
+            "true",
+            "--normalize-parentheses",
+            "true",
+        ],
+    );
+}
+
+#[test]
+fn markdown_is_checked() {
+    let output = run(&["--check", "tests/files/markdown/runbook/input.md"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("input.md"));
+
+    let output = run(&["--check", "tests/files/markdown/runbook/expected.md"]);
+    assert!(output.status.success());
+}
+
+fn vcl_files(dir: &Path, found: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            vcl_files(&path, found);
+        } else if path.extension().is_some_and(|e| e == "vcl") {
+            found.push(path);
+        }
+    }
+}
+
+#[test]
+fn all_files_are_formatted_idempotently() {
+    let mut files = vec![];
+    vcl_files(Path::new("tests/files"), &mut files);
+    assert!(!files.is_empty());
+    for file in files {
+        let data = std::fs::read(&file).unwrap();
+        let output = process_vcl_with_args(&data, &["--fragment", "auto"]);
+        let stderr = std::str::from_utf8(&output.stderr).unwrap();
+        assert!(
+            output.status.success()
+                || stderr.starts_with("Error: Unknown token")
+                || stderr.starts_with("Error: Unexpected token"),
+            "{}: {stderr}",
+            file.display()
+        );
+    }
 }
@@ -0,0 +1,734 @@
+use proptest::{prelude::*, sample::select};
+use vcl_formatter::{
+    ast::*,
+    ast_emitter::AstEmitter,
+    lexer::{self, lex_trivia, TokenData, TriviaToken},
+    parser::{self, binary_precedence, precedence},
+    style::Style,
+};
+
+/// Whitespace and comments put before generated tokens; plain spacing is listed several times
+/// to keep comments from dominating.
+const TRIVIA: &[&str] = &[
+    "",
+    "",
+    "",
+    " ",
+    " ",
+    "\n",
+    "\n\n",
+    "/* block */ ",
+    "# hash\n",
+    "// line\n",
+    "\n/* multi\n   line */\n",
+];
+const IDENTS: &[&str] = &["req.url", "req.http.host", "client.ip", "beresp.ttl", "x"];
+const LITERALS: &[&str] = &[
+    "\"str\"",
+    "{\"long\"}",
+    "1",
+    "-5",
+    "2.5",
+    "10s",
+    "1KB",
+    "true",
+];
+const FUNCTIONS: &[&str] = &["regsub", "std.tolower", "director.backend"];
+const BINARY_OPERATORS: &[&str] = &[
+    "+", "-", "*", "/", "&&", "||", "==", "!=", "~", "!~", ">", "<", ">=", "<=",
+];
+const SUBS: &[&str] = &["vcl_recv", "vcl_deliver", "normalize"];
+const PROPERTIES: &[&str] = &[".host", ".port", ".url", ".probe"];
+
+fn tok(content: &'static str) -> impl Strategy<Value = TokenData<'static>> {
+    select(TRIVIA).prop_map(move |t| TokenData::synthetic(content, t))
+}
+
+fn tok_of(contents: &'static [&'static str]) -> impl Strategy<Value = TokenData<'static>> {
+    (select(contents), select(TRIVIA)).prop_map(|(c, t)| TokenData::synthetic(c, t))
+}
+
+/// Parenthesizes `e` if it would otherwise be parsed differently as an operand binding at
+/// least as tightly as `min_precedence`.
+fn operand(e: Expression<'static>, min_precedence: u8) -> Box<Expression<'static>> {
+    if precedence(&e) >= min_precedence {
+        return Box::new(e);
+    }
+    Box::new(Expression::Parenthesized {
+        lparen: TokenData::synthetic("(", ""),
+        expr: Box::new(e),
+        rparen: TokenData::synthetic(")", ""),
+    })
+}
+
+fn delimited<T: std::fmt::Debug>(
+    item: impl Strategy<Value = T> + Clone,
+) -> impl Strategy<Value = DelimitedList<T, TokenData<'static>>> {
+    prop::option::of((prop::collection::vec((item.clone(), tok(",")), 0..3), item)).prop_map(
+        |items| match items {
+            Some((pairs, last_item)) => DelimitedList::WithItems { pairs, last_item },
+            None => DelimitedList::Empty,
+        },
+    )
+}
+
+fn ident_call(
+    expr: impl Strategy<Value = Expression<'static>> + Clone,
+) -> impl Strategy<Value = IdentCallExpression<'static>> {
+    let arg = prop_oneof![
+        expr.clone().prop_map(FunctionCallArg::Positional),
+        (tok_of(&["a", "b"]), tok("="), expr)
+            .prop_map(|(name, op, value)| FunctionCallArg::Named { name, op, value }),
+    ];
+    (tok_of(FUNCTIONS), tok("("), delimited(arg), tok(")")).prop_map(
+        |(name, lparen, args, rparen)| IdentCallExpression {
+            name,
+            lparen,
+            args: Box::new(args),
+            rparen,
+        },
+    )
+}
+
+fn expression() -> BoxedStrategy<Expression<'static>> {
+    let leaf = prop_oneof![
+        tok_of(IDENTS).prop_map(Expression::Ident),
+        tok_of(LITERALS).prop_map(Expression::Literal),
+    ];
+    leaf.prop_recursive(6, 48, 3, |inner| {
+        prop_oneof![
+            3 => (inner.clone(), tok_of(BINARY_OPERATORS), inner.clone()).prop_map(|(l, op, r)| {
+                let p = binary_precedence(op.content).unwrap_or(0);
+                Expression::Binary {
+                    left: operand(l, p),
+                    op,
+                    right: operand(r, p + 1),
+                }
+            }),
+            1 => (tok("!"), inner.clone()).prop_map(|(op, e)| Expression::Neg {
+                op,
+                expr: operand(e, u8::MAX),
+            }),
+            1 => (tok("("), inner.clone(), tok(")")).prop_map(|(lparen, e, rparen)| {
+                Expression::Parenthesized {
+                    lparen,
+                    expr: Box::new(e),
+                    rparen,
+                }
+            }),
+            1 => ident_call(inner).prop_map(Expression::IdentCall),
+        ]
+    })
+    .boxed()
+}
+
+fn include() -> impl Strategy<Value = IncludeData<'static>> {
+    (
+        tok("include"),
+        tok_of(&["\"a.vcl\"", "\"lib/b.vcl\""]),
+        tok(";"),
+    )
+        .prop_map(|(include, name, semi)| IncludeData {
+            include,
+            name,
+            semi,
+        })
+}
+
+fn statement() -> impl Strategy<Value = Statement<'static>> {
+    let simple = prop_oneof![
+        (
+            tok("set"),
+            tok_of(IDENTS),
+            tok_of(&["=", "+=", "-="]),
+            expression(),
+            tok(";")
+        )
+            .prop_map(|(set, ident, op, expr, semi)| Statement::Set {
+                set,
+                ident,
+                op,
+                expr,
+                semi
+            }),
+        (tok("unset"), tok_of(IDENTS), tok(";"))
+            .prop_map(|(unset, ident, semi)| Statement::Unset { unset, ident, semi }),
+        (tok("call"), tok_of(SUBS), tok(";")).prop_map(|(call, ident, semi)| Statement::Call {
+            call,
+            ident,
+            semi
+        }),
+        (ident_call(expression()), tok(";"))
+            .prop_map(|(expr, semi)| Statement::IdentCall { expr, semi }),
+        (
+            tok("return"),
+            tok("("),
+            tok_of(&["pass", "hash", "synth"]),
+            prop::option::of((tok("("), delimited(expression()), tok(")"))),
+            tok(")"),
+            tok(";")
+        )
+            .prop_map(
+                |(return_t, lparen, name, args, rparen, semi)| Statement::Return {
+                    return_t,
+                    lparen,
+                    name,
+                    args: args.map(|(lparen, args, rparen)| ReturnArgs {
+                        lparen,
+                        args,
+                        rparen
+                    }),
+                    rparen,
+                    semi
+                }
+            ),
+        (
+            tok("new"),
+            tok("d"),
+            tok("="),
+            ident_call(expression()),
+            tok(";")
+        )
+            .prop_map(|(new, name, op, value, semi)| Statement::New {
+                new,
+                name,
+                op,
+                value,
+                semi
+            }),
+        include().prop_map(Statement::Include),
+    ];
+    simple.prop_recursive(3, 24, 3, |inner| {
+        let body = prop::collection::vec(inner, 0..3);
+        let elseif_keyword = prop_oneof![
+            tok("elseif").prop_map(|t| vec![t]),
+            (tok("else"), tok("if")).prop_map(|(e, i)| vec![e, i]),
+        ];
+        let elseif = (
+            elseif_keyword,
+            tok("("),
+            expression(),
+            tok(")"),
+            tok("{"),
+            body.clone(),
+            tok("}"),
+        )
+            .prop_map(
+                |(elseif, lparen, condition, rparen, lbrace, body, rbrace)| ElseIfStatement {
+                    elseif,
+                    lparen,
+                    condition,
+                    rparen,
+                    lbrace,
+                    body,
+                    rbrace,
+                },
+            );
+        let else_st = (tok("else"), tok("{"), body.clone(), tok("}")).prop_map(
+            |(else_t, lbrace, body, rbrace)| ElseStatement {
+                else_t,
+                lbrace,
+                body,
+                rbrace,
+            },
+        );
+        (
+            tok("if"),
+            tok("("),
+            expression(),
+            tok(")"),
+            tok("{"),
+            body,
+            tok("}"),
+            prop::collection::vec(elseif, 0..3),
+            prop::option::of(else_st),
+        )
+            .prop_map(
+                |(if_t, lparen, condition, rparen, lbrace, body, rbrace, elseifs, else_st)| {
+                    Statement::If {
+                        if_t,
+                        lparen,
+                        condition,
+                        rparen,
+                        lbrace,
+                        body,
+                        rbrace,
+                        elseifs,
+                        else_st,
+                    }
+                },
+            )
+    })
+}
+
+fn backend_properties() -> impl Strategy<Value = Vec<BackendProperty<'static>>> {
+    let value = prop_oneof![
+        (expression(), tok(";")).prop_map(|(expr, semi)| BackendValue::Expression { expr, semi }),
+        (
+            prop::collection::vec(
+                tok_of(&["\"GET / HTTP/1.1\"", "\"Connection: close\""]),
+                2..4
+            ),
+            tok(";")
+        )
+            .prop_map(|(strings, semi)| BackendValue::StringList { strings, semi }),
+    ];
+    let property = (tok_of(PROPERTIES), tok("="), value)
+        .prop_map(|(name, op, value)| BackendProperty { name, op, value })
+        .boxed();
+    let composite = (
+        tok_of(PROPERTIES),
+        tok("="),
+        tok("{"),
+        prop::collection::vec(property.clone(), 0..3),
+        tok("}"),
+    )
+        .prop_map(|(name, op, lbrace, properties, rbrace)| BackendProperty {
+            name,
+            op,
+            value: BackendValue::Composite {
+                lbrace,
+                properties,
+                rbrace,
+            },
+        });
+    prop::collection::vec(prop_oneof![3 => property, 1 => composite], 0..4)
+}
+
+fn top_level_declaration() -> impl Strategy<Value = TopLevelDeclaration<'static>> {
+    let acl_entry = (
+        tok_of(&["\"localhost\"", "\"10.0.0.0\""]),
+        prop::option::of((tok("/"), tok_of(&["8", "24"]))),
+        tok(";"),
+    )
+        .prop_map(|(value, mask, semi)| AclEntry {
+            value,
+            mask: mask.map(|(op, mask)| MaskData { op, mask }),
+            semi,
+        });
+    prop_oneof![
+        (tok("vcl"), tok_of(&["4.0", "4.1"]), tok(";"))
+            .prop_map(|(vcl, number, semi)| TopLevelDeclaration::VclVersion { vcl, number, semi }),
+        (
+            tok("import"),
+            tok_of(&["std", "directors"]),
+            prop::option::of((tok("from"), tok("\"/usr/lib/vmod.so\""))),
+            tok(";")
+        )
+            .prop_map(|(import, name, from, semi)| TopLevelDeclaration::Import {
+                import,
+                name,
+                from: from.map(|(from, value)| FromData { from, value }),
+                semi
+            }),
+        include().prop_map(TopLevelDeclaration::Include),
+        (
+            tok("acl"),
+            tok_of(&["local", "purgers"]),
+            tok("{"),
+            prop::collection::vec(acl_entry, 0..4),
+            tok("}")
+        )
+            .prop_map(
+                |(acl, name, lbrace, entries, rbrace)| TopLevelDeclaration::Acl {
+                    acl,
+                    name,
+                    lbrace,
+                    entries,
+                    rbrace
+                }
+            ),
+        (
+            tok("backend"),
+            tok_of(&["default", "api"]),
+            tok("{"),
+            backend_properties(),
+            tok("}")
+        )
+            .prop_map(|(backend, name, lbrace, properties, rbrace)| {
+                TopLevelDeclaration::Backend(BackendData::Defined {
+                    backend,
+                    name,
+                    lbrace,
+                    properties,
+                    rbrace,
+                })
+            }),
+        (tok("backend"), tok("default"), tok("none"), tok(";")).prop_map(
+            |(backend, name, none, semi)| {
+                TopLevelDeclaration::Backend(BackendData::None {
+                    backend,
+                    name,
+                    none,
+                    semi,
+                })
+            }
+        ),
+        (
+            tok("probe"),
+            tok("healthy"),
+            tok("{"),
+            backend_properties(),
+            tok("}")
+        )
+            .prop_map(|(probe, name, lbrace, properties, rbrace)| {
+                TopLevelDeclaration::Probe {
+                    probe,
+                    name,
+                    lbrace,
+                    properties,
+                    rbrace,
+                }
+            }),
+        (
+            tok("sub"),
+            tok_of(SUBS),
+            tok("{"),
+            prop::collection::vec(statement(), 0..5),
+            tok("}")
+        )
+            .prop_map(
+                |(sub, name, lbrace, statements, rbrace)| TopLevelDeclaration::Sub {
+                    sub,
+                    name,
+                    lbrace,
+                    statements,
+                    rbrace
+                }
+            ),
+    ]
+}
+
+fn source_file() -> impl Strategy<Value = SourceFile<'static>> {
+    (
+        prop::collection::vec(top_level_declaration(), 0..6),
+        tok(""),
+    )
+        .prop_map(|(declarations, eof)| SourceFile { declarations, eof })
+}
+
+/// The formatter always writes `elseif` as `else if`.
+fn shape(tree: &impl std::fmt::Debug) -> String {
+    format!("{tree:?}").replace(r#"elseif: ["elseif"]"#, r#"elseif: ["else", "if"]"#)
+}
+
+fn comments<'a>(pre_trivia: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut result: Vec<String> = pre_trivia
+        .flat_map(|t| lex_trivia(t).unwrap())
+        .filter_map(|t| match t {
+            TriviaToken::LineComment(s)
+            | TriviaToken::MultilineComment(s)
+            | TriviaToken::InlineCCode(s) => Some(s.to_string()),
+            TriviaToken::Newline => None,
+        })
+        .collect();
+    result.sort();
+    result
+}
+
+proptest! {
+    #[test]
+    fn generated_files_survive_formatting(sf in source_file()) {
+        let mut out = vec![];
+        AstEmitter::new(&mut out, &Style::default(), 0)
+            .emit(&sf)
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let tokens = lexer::lex(&text).unwrap_or_else(|e| panic!("{e:?}\n{text}"));
+        let parsed = parser::parse(&tokens).unwrap_or_else(|e| panic!("{e:?}\n{text}"));
+
+        prop_assert_eq!(shape(&sf), shape(&parsed), "{}", text);
+        prop_assert_eq!(
+            comments(TokenCollector::collect(&sf).into_iter()),
+            comments(tokens.iter().filter_map(|t| t.data()).map(|d| d.pre_trivia)),
+            "{}",
+            text
+        );
+    }
+}
+
+/// Gathers the trivia of every token in a syntax tree.
+#[derive(Default)]
+struct TokenCollector<'a> {
+    trivia: Vec<&'a str>,
+}
+
+impl<'a> TokenCollector<'a> {
+    fn collect(sf: &SourceFile<'a>) -> Vec<&'a str> {
+        let mut c = Self::default();
+        for td in &sf.declarations {
+            c.declaration(td);
+        }
+        c.token(&sf.eof);
+        c.trivia
+    }
+
+    fn token(&mut self, t: &TokenData<'a>) {
+        self.trivia.push(t.pre_trivia);
+    }
+
+    fn declaration(&mut self, td: &TopLevelDeclaration<'a>) {
+        match td {
+            TopLevelDeclaration::VclVersion { vcl, number, semi } => {
+                [vcl, number, semi].into_iter().for_each(|t| self.token(t))
+            }
+            TopLevelDeclaration::Import {
+                import,
+                name,
+                from,
+                semi,
+            } => {
+                self.token(import);
+                self.token(name);
+                if let Some(f) = from {
+                    self.token(&f.from);
+                    self.token(&f.value);
+                }
+                self.token(semi);
+            }
+            TopLevelDeclaration::Include(i) => self.include(i),
+            TopLevelDeclaration::Acl {
+                acl,
+                name,
+                lbrace,
+                entries,
+                rbrace,
+            } => {
+                [acl, name, lbrace].into_iter().for_each(|t| self.token(t));
+                for e in entries {
+                    self.token(&e.value);
+                    if let Some(m) = &e.mask {
+                        self.token(&m.op);
+                        self.token(&m.mask);
+                    }
+                    self.token(&e.semi);
+                }
+                self.token(rbrace);
+            }
+            TopLevelDeclaration::Backend(BackendData::Defined {
+                backend: keyword,
+                name,
+                lbrace,
+                properties,
+                rbrace,
+            })
+            | TopLevelDeclaration::Probe {
+                probe: keyword,
+                name,
+                lbrace,
+                properties,
+                rbrace,
+            } => {
+                [keyword, name, lbrace]
+                    .into_iter()
+                    .for_each(|t| self.token(t));
+                self.properties(properties);
+                self.token(rbrace);
+            }
+            TopLevelDeclaration::Backend(BackendData::None {
+                backend,
+                name,
+                none,
+                semi,
+            }) => [backend, name, none, semi]
+                .into_iter()
+                .for_each(|t| self.token(t)),
+            TopLevelDeclaration::Sub {
+                sub,
+                name,
+                lbrace,
+                statements,
+                rbrace,
+            } => {
+                [sub, name, lbrace].into_iter().for_each(|t| self.token(t));
+                self.statements(statements);
+                self.token(rbrace);
+            }
+            TopLevelDeclaration::Placeholder { token, semi } => {
+                self.token(token);
+                semi.iter().for_each(|t| self.token(t));
+            }
+        }
+    }
+
+    fn include(&mut self, i: &IncludeData<'a>) {
+        [&i.include, &i.name, &i.semi]
+            .into_iter()
+            .for_each(|t| self.token(t));
+    }
+
+    fn properties(&mut self, properties: &[BackendProperty<'a>]) {
+        for p in properties {
+            self.token(&p.name);
+            self.token(&p.op);
+            match &p.value {
+                BackendValue::Expression { expr, semi } => {
+                    self.expression(expr);
+                    self.token(semi);
+                }
+                BackendValue::StringList { strings, semi } => {
+                    strings.iter().for_each(|t| self.token(t));
+                    self.token(semi);
+                }
+                BackendValue::Composite {
+                    lbrace,
+                    properties,
+                    rbrace,
+                } => {
+                    self.token(lbrace);
+                    self.properties(properties);
+                    self.token(rbrace);
+                }
+            }
+        }
+    }
+
+    fn statements(&mut self, statements: &[Statement<'a>]) {
+        for st in statements {
+            self.statement(st);
+        }
+    }
+
+    fn statement(&mut self, st: &Statement<'a>) {
+        match st {
+            Statement::Set {
+                set,
+                ident,
+                op,
+                expr,
+                semi,
+            } => {
+                [set, ident, op].into_iter().for_each(|t| self.token(t));
+                self.expression(expr);
+                self.token(semi);
+            }
+            Statement::Unset {
+                unset: keyword,
+                ident,
+                semi,
+            }
+            | Statement::Call {
+                call: keyword,
+                ident,
+                semi,
+            } => [keyword, ident, semi]
+                .into_iter()
+                .for_each(|t| self.token(t)),
+            Statement::IdentCall { expr, semi } => {
+                self.ident_call(expr);
+                self.token(semi);
+            }
+            Statement::If {
+                if_t,
+                lparen,
+                condition,
+                rparen,
+                lbrace,
+                body,
+                rbrace,
+                elseifs,
+                else_st,
+            } => {
+                [if_t, lparen].into_iter().for_each(|t| self.token(t));
+                self.expression(condition);
+                [rparen, lbrace].into_iter().for_each(|t| self.token(t));
+                self.statements(body);
+                self.token(rbrace);
+                for ei in elseifs {
+                    ei.elseif.iter().for_each(|t| self.token(t));
+                    self.token(&ei.lparen);
+                    self.expression(&ei.condition);
+                    self.token(&ei.rparen);
+                    self.token(&ei.lbrace);
+                    self.statements(&ei.body);
+                    self.token(&ei.rbrace);
+                }
+                if let Some(e) = else_st {
+                    self.token(&e.else_t);
+                    self.token(&e.lbrace);
+                    self.statements(&e.body);
+                    self.token(&e.rbrace);
+                }
+            }
+            Statement::Return {
+                return_t,
+                lparen,
+                name,
+                args,
+                rparen,
+                semi,
+            } => {
+                [return_t, lparen, name]
+                    .into_iter()
+                    .for_each(|t| self.token(t));
+                if let Some(a) = args {
+                    self.token(&a.lparen);
+                    for (e, comma) in a.args.iter() {
+                        self.expression(e);
+                        comma.into_iter().for_each(|t| self.token(t));
+                    }
+                    self.token(&a.rparen);
+                }
+                [rparen, semi].into_iter().for_each(|t| self.token(t));
+            }
+            Statement::New {
+                new,
+                name,
+                op,
+                value,
+                semi,
+            } => {
+                [new, name, op].into_iter().for_each(|t| self.token(t));
+                self.ident_call(value);
+                self.token(semi);
+            }
+            Statement::Include(i) => self.include(i),
+            Statement::Placeholder { token, semi } => {
+                self.token(token);
+                semi.iter().for_each(|t| self.token(t));
+            }
+        }
+    }
+
+    fn ident_call(&mut self, call: &IdentCallExpression<'a>) {
+        self.token(&call.name);
+        self.token(&call.lparen);
+        for (arg, comma) in call.args.iter() {
+            match arg {
+                FunctionCallArg::Named { name, op, value } => {
+                    self.token(name);
+                    self.token(op);
+                    self.expression(value);
+                }
+                FunctionCallArg::Positional(e) => self.expression(e),
+            }
+            comma.into_iter().for_each(|t| self.token(t));
+        }
+        self.token(&call.rparen);
+    }
+
+    fn expression(&mut self, e: &Expression<'a>) {
+        match e {
+            Expression::Ident(t) | Expression::Literal(t) => self.token(t),
+            Expression::Neg { op, expr } => {
+                self.token(op);
+                self.expression(expr);
+            }
+            Expression::Binary { left, op, right } => {
+                self.expression(left);
+                self.token(op);
+                self.expression(right);
+            }
+            Expression::IdentCall(call) => self.ident_call(call),
+            Expression::Parenthesized {
+                lparen,
+                expr,
+                rparen,
+            } => {
+                self.token(lparen);
+                self.expression(expr);
+                self.token(rparen);
+            }
+        }
+    }
+}